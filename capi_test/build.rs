@@ -0,0 +1,15 @@
+extern crate cc;
+
+fn main() {
+    // Compile the C half of the integration test. This only adds a link search path: the library
+    // is linked into `tests/capi.rs` via a `#[link]` attribute.
+    println!("cargo:rerun-if-changed=../include/maybe_box.h");
+    println!("cargo:rerun-if-changed=capi_test.c");
+    cc::Build::new()
+        .file("capi_test.c")
+        .include("../include")
+        .warnings_into_errors(true)
+        .cargo_metadata(false)
+        .compile("maybe_box_capi_test");
+    println!("cargo:rustc-link-search=native={}", std::env::var("OUT_DIR").unwrap());
+}
@@ -0,0 +1,71 @@
+/*
+ * C half of tests/capi.rs. Built by build.rs.
+ */
+
+#include "maybe_box.h"
+
+/* Whether *mb has a type name. Its exact contents aren't guaranteed by Rust, so aren't checked. */
+static bool has_type_name(const maybe_box_erased *mb) {
+    size_t len;
+    const char *name = maybe_box_erased_type_name(mb, &len);
+    return name != NULL && len > 0;
+}
+
+/* Take ownership of *mb, which must hold a cloneable Rust String, and give back two clones of it
+ * in out[0] and out[1]. *reference must hold a String too. Returns 0 on success or the number of
+ * the check that failed. */
+int capi_test_clone_string(
+    maybe_box_erased *mb,
+    const maybe_box_erased *reference,
+    maybe_box_erased out[2]
+) {
+    if (!maybe_box_erased_is_cloneable(mb)) {
+        return 1;
+    }
+    if (!maybe_box_erased_same_type(mb, reference) || !has_type_name(mb)) {
+        return 2;
+    }
+    if (!maybe_box_erased_clone(mb, &out[0])) {
+        return 3;
+    }
+    if (!maybe_box_erased_clone(&out[0], &out[1])) {
+        return 4;
+    }
+    if (!maybe_box_erased_same_type(mb, &out[1])) {
+        return 5;
+    }
+    maybe_box_erased_drop(mb);
+    return 0;
+}
+
+/* Get the size of a maybe_box_erased, and write the offset of its vtable field to
+ * *vtable_offset, so the layout can be compared with Rust's. */
+size_t capi_test_erased_layout(size_t *vtable_offset) {
+    *vtable_offset = offsetof(maybe_box_erased, vtable);
+    return sizeof(maybe_box_erased);
+}
+
+/* Check that *a isn't cloneable, is the same type as *reference and isn't the same type as *b,
+ * then drop *a and *b. Returns 0 on success or the number of the check that failed. */
+int capi_test_drop_uncloneable(
+    maybe_box_erased *a,
+    maybe_box_erased *b,
+    const maybe_box_erased *reference
+) {
+    maybe_box_erased out;
+    if (maybe_box_erased_is_cloneable(a)) {
+        return 1;
+    }
+    if (maybe_box_erased_clone(a, &out)) {
+        return 2;
+    }
+    if (maybe_box_erased_same_type(a, b)) {
+        return 3;
+    }
+    if (!maybe_box_erased_same_type(a, reference) || !has_type_name(a)) {
+        return 4;
+    }
+    maybe_box_erased_drop(a);
+    maybe_box_erased_drop(b);
+    return 0;
+}
@@ -0,0 +1,4 @@
+//! Tests for the `maybe_box` C API, in their own crate so that building `maybe_box` with the
+//! `capi` feature never compiles C code. The C half of the tests is in `capi_test.c`, and the Rust
+//! half is in `tests/capi.rs`. `tests/header.rs` checks that the prototypes in `maybe_box.h` match
+//! the functions in `src/capi.rs`.
@@ -0,0 +1,58 @@
+extern crate maybe_box;
+
+use std::mem::{self, MaybeUninit};
+use std::rc::Rc;
+
+use maybe_box::{MaybeBox, ErasedMaybeBox};
+
+// `ErasedMaybeBox`'s vtable isn't FFI-safe, but C only ever sees it as an opaque pointer.
+#[allow(improper_ctypes)]
+#[link(name = "maybe_box_capi_test", kind = "static")]
+extern "C" {
+    fn capi_test_clone_string(
+        mb: *mut ErasedMaybeBox,
+        reference: *const ErasedMaybeBox,
+        out: *mut [ErasedMaybeBox; 2],
+    ) -> i32;
+    fn capi_test_drop_uncloneable(
+        a: *mut ErasedMaybeBox,
+        b: *mut ErasedMaybeBox,
+        reference: *const ErasedMaybeBox,
+    ) -> i32;
+    fn capi_test_erased_layout(vtable_offset: *mut usize) -> usize;
+}
+
+#[test]
+fn layout_matches_c() {
+    let mut vtable_offset = 0;
+    let size = unsafe { capi_test_erased_layout(&mut vtable_offset) };
+    assert_eq!(size, mem::size_of::<ErasedMaybeBox>());
+    assert_eq!(size, 2 * mem::size_of::<usize>());
+    assert_eq!(vtable_offset, mem::size_of::<usize>());
+    assert_eq!(mem::align_of::<ErasedMaybeBox>(), mem::align_of::<usize>());
+}
+
+#[test]
+fn clone_from_c() {
+    let mut mb = MaybeUninit::new(ErasedMaybeBox::new_clone(MaybeBox::new(String::from("hello"))));
+    let reference = ErasedMaybeBox::new(MaybeBox::new(String::new()));
+    let mut out = MaybeUninit::<[ErasedMaybeBox; 2]>::uninit();
+    let res = unsafe { capi_test_clone_string(mb.as_mut_ptr(), &reference, out.as_mut_ptr()) };
+    assert_eq!(res, 0);
+
+    let [a, b] = unsafe { out.assume_init() };
+    assert_eq!(a.downcast::<String>().unwrap().into_inner(), "hello");
+    assert_eq!(&*b.downcast::<String>().unwrap(), "hello");
+}
+
+#[test]
+fn drop_from_c() {
+    let rc = Rc::new(());
+    let mut a = MaybeUninit::new(ErasedMaybeBox::new(MaybeBox::new(123u32)));
+    let mut b = MaybeUninit::new(ErasedMaybeBox::new(MaybeBox::new(rc.clone())));
+    assert_eq!(Rc::strong_count(&rc), 2);
+    let reference = ErasedMaybeBox::new(MaybeBox::new(0u32));
+    let res = unsafe { capi_test_drop_uncloneable(a.as_mut_ptr(), b.as_mut_ptr(), &reference) };
+    assert_eq!(res, 0);
+    assert_eq!(Rc::strong_count(&rc), 1);
+}
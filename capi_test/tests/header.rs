@@ -0,0 +1,127 @@
+//! Checks that the prototypes in `include/maybe_box.h` match the `extern "C"` functions in
+//! `src/capi.rs`, so that the hand-written header can't drift from the Rust signatures.
+
+extern crate syn;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// A function's return type and parameter types, each written as C tokens separated by spaces,
+/// eg. `const maybe_box_erased *`.
+type Signature = (String, Vec<String>);
+
+fn read(path: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("..").join(path);
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("failed to read {:?}: {}", path, e))
+}
+
+/// Split C source into identifiers and `*`s, dropping anything else.
+fn c_tokens(s: &str) -> Vec<String> {
+    s.replace('*', " * ")
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+/// The type part of a C declaration such as `const char *name`, which is all but the last token.
+fn c_decl_type(decl: &str) -> String {
+    let mut tokens = c_tokens(decl);
+    tokens.pop();
+    tokens.join(" ")
+}
+
+/// The signatures of the functions declared in the header, keyed by name.
+fn header_signatures() -> BTreeMap<String, Signature> {
+    let mut header = read("include/maybe_box.h");
+    while let Some(start) = header.find("/*") {
+        let end = start + header[start..].find("*/").unwrap() + 2;
+        header.replace_range(start..end, " ");
+    }
+    let header: String = header
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut sigs = BTreeMap::new();
+    for decl in header.split(';') {
+        let decl = decl.trim();
+        let open = match decl.find('(') {
+            Some(open) if decl.ends_with(')') && !decl.contains('{') => open,
+            _ => continue,
+        };
+        let head = &decl[..open];
+        let name = c_tokens(head).pop().unwrap();
+        if !name.starts_with("maybe_box_") {
+            continue;
+        }
+        let params = decl[open + 1..decl.len() - 1].trim();
+        let params = if params == "void" {
+            Vec::new()
+        } else {
+            params.split(',').map(c_decl_type).collect()
+        };
+        sigs.insert(name, (c_decl_type(head), params));
+    }
+    sigs
+}
+
+/// The C spelling of a Rust type used in the C API.
+fn c_type(ty: &syn::Type) -> String {
+    match ty {
+        syn::Type::Ptr(ptr) => {
+            let constness = if ptr.const_token.is_some() { "const " } else { "" };
+            format!("{}{} *", constness, c_type(&ptr.elem))
+        },
+        syn::Type::Path(path) => {
+            let name = path.path.segments.last().unwrap().ident.to_string();
+            match &name[..] {
+                "ErasedMaybeBox" => "maybe_box_erased",
+                "usize" => "size_t",
+                "c_char" => "char",
+                "c_void" => "void",
+                "bool" => "bool",
+                _ => panic!("no C equivalent for {} in src/capi.rs", name),
+            }.to_owned()
+        },
+        syn::Type::Tuple(tuple) if tuple.elems.is_empty() => "void".to_owned(),
+        _ => panic!("unexpected type in src/capi.rs"),
+    }
+}
+
+/// The signatures of the `#[no_mangle] extern "C"` functions in `src/capi.rs`, keyed by name.
+fn rust_signatures() -> BTreeMap<String, Signature> {
+    let file = syn::parse_file(&read("src/capi.rs")).unwrap();
+    let mut sigs = BTreeMap::new();
+    for item in file.items {
+        let func = match item {
+            syn::Item::Fn(func) => func,
+            _ => continue,
+        };
+        if !func.attrs.iter().any(|attr| attr.path().is_ident("no_mangle")) {
+            continue;
+        }
+        let sig = &func.sig;
+        assert!(sig.abi.is_some(), "{} isn't extern \"C\"", sig.ident);
+        let ret = match &sig.output {
+            syn::ReturnType::Default => "void".to_owned(),
+            syn::ReturnType::Type(_, ty) => c_type(ty),
+        };
+        let params = sig.inputs.iter().map(|arg| match arg {
+            syn::FnArg::Typed(arg) => c_type(&arg.ty),
+            syn::FnArg::Receiver(_) => unreachable!(),
+        });
+        let params = params.map(|ty| c_tokens(&ty).join(" ")).collect();
+        sigs.insert(sig.ident.to_string(), (c_tokens(&ret).join(" "), params));
+    }
+    sigs
+}
+
+#[test]
+fn header_matches_rust() {
+    let header = header_signatures();
+    let rust = rust_signatures();
+    assert!(!rust.is_empty());
+    assert_eq!(header, rust);
+}
@@ -10,7 +10,7 @@ fn main() {
 
     // Extract the data back out again.
     let my_bool = mb.into_inner();
-    assert_eq!(my_bool, true);
+    assert!(my_bool);
 
     // Wrap a String into a MaybeBox
     // Because a String is too big to fit into the size of a pointer, this
@@ -0,0 +1,66 @@
+/*
+ * C interface to maybe_box, available when the crate is built with the `capi` feature.
+ *
+ * A maybe_box_erased holds a Rust value of a type that C doesn't need to know about. It can be
+ * cloned, dropped and have its type queried from C, and passed back to Rust to be downcast to its
+ * original type. It must be dropped exactly once, either with maybe_box_erased_drop or by
+ * handing it back to Rust.
+ *
+ * The functions below abort the process if the Rust code they call into panics.
+ *
+ * This header is written by hand. The maybe_box_capi_test crate checks that its prototypes match
+ * the extern "C" functions in src/capi.rs.
+ */
+
+#ifndef MAYBE_BOX_H
+#define MAYBE_BOX_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+struct maybe_box_vtable;
+
+/* A type-erased MaybeBox. Corresponds to Rust's `maybe_box::ErasedMaybeBox`. The fields are
+ * private to Rust. */
+typedef struct maybe_box_erased {
+    void *data;
+    const struct maybe_box_vtable *vtable;
+} maybe_box_erased;
+
+/* Check that the layout matches the Rust struct's, which is two words. */
+#ifdef __cplusplus
+#define MAYBE_BOX_STATIC_ASSERT static_assert
+#else
+#define MAYBE_BOX_STATIC_ASSERT _Static_assert
+#endif
+MAYBE_BOX_STATIC_ASSERT(sizeof(maybe_box_erased) == 2 * sizeof(void *),
+                        "maybe_box_erased must be two words");
+MAYBE_BOX_STATIC_ASSERT(offsetof(maybe_box_erased, vtable) == sizeof(void *),
+                        "maybe_box_erased.vtable must be the second word");
+#undef MAYBE_BOX_STATIC_ASSERT
+
+/* Clone *mb into *out. Returns false, leaving *out untouched, if *mb isn't cloneable. */
+bool maybe_box_erased_clone(const maybe_box_erased *mb, maybe_box_erased *out);
+
+/* Drop the value held in *mb. *mb must not be used afterwards. */
+void maybe_box_erased_drop(maybe_box_erased *mb);
+
+/* Whether *mb can be cloned with maybe_box_erased_clone. */
+bool maybe_box_erased_is_cloneable(const maybe_box_erased *mb);
+
+/* Whether *a and *b hold values of the same type. */
+bool maybe_box_erased_same_type(const maybe_box_erased *a, const maybe_box_erased *b);
+
+/* Get the name of the type held in *mb. The name is UTF-8 and is NOT nul-terminated; its length
+ * in bytes is written to *len. The returned pointer remains valid forever. */
+const char *maybe_box_erased_type_name(const maybe_box_erased *mb, size_t *len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MAYBE_BOX_H */
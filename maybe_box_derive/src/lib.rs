@@ -0,0 +1,336 @@
+//! `#[derive(WordEnum)]`, for packing an enum into a single word with `maybe_box`.
+//!
+//! This is re-exported by `maybe_box` when its `derive` feature is enabled. See the
+//! `maybe_box::word_enum` module for a description of what's generated.
+//!
+//! Deriving `WordEnum` for an enum with more variants than there are spare bits in a word for is a
+//! compile-time error:
+//!
+//! ```compile_fail
+//! #[macro_use]
+//! extern crate maybe_box_derive;
+//! extern crate maybe_box;
+//!
+//! #[derive(WordEnum)]
+//! enum TooMany { A, B, C, D, E, F, G, H, I }
+//! # fn main() {}
+//! ```
+//!
+//! Enums with fields that may contain uninitialized bytes can still be derived, but their packed
+//! form can't be converted to a raw word unless every field implements `maybe_box::NoUninit` or
+//! belongs to a variant marked `#[word_enum(boxed)]`:
+//!
+//! ```compile_fail
+//! #[macro_use]
+//! extern crate maybe_box_derive;
+//! extern crate maybe_box;
+//!
+//! use maybe_box::word_enum::WordEnum;
+//!
+//! #[derive(WordEnum)]
+//! enum Padded { Pair((u8, u16)), Empty }
+//!
+//! # fn main() {
+//! let raw = Padded::Pair((1, 2)).pack().into_raw();
+//! # }
+//! ```
+//!
+//! Marking a variant `#[word_enum(boxed)]` when its field is stored inline fails to compile too:
+//!
+//! ```compile_fail
+//! #[macro_use]
+//! extern crate maybe_box_derive;
+//! extern crate maybe_box;
+//!
+//! use maybe_box::word_enum::WordEnum;
+//!
+//! #[derive(WordEnum)]
+//! enum Small { #[word_enum(boxed)] Byte(u8), Empty }
+//!
+//! # fn main() {
+//! let raw = Small::Byte(1).pack().into_raw();
+//! # }
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use syn::{Data, DeriveInput, Fields, Ident, Type};
+
+/// Derive `maybe_box::WordEnum` for an enum whose variants each have either no fields or a single
+/// unnamed field. This generates a `<Name>Word` struct holding the packed enum.
+///
+/// A variant whose field is always boxed can be marked `#[word_enum(boxed)]`, so that its field
+/// doesn't need to implement `NoUninit` for the packed enum to be converted to a raw word.
+#[proc_macro_derive(WordEnum, attributes(word_enum))]
+pub fn derive_word_enum(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match word_enum(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+struct Variant<'a> {
+    ident: &'a Ident,
+    field: Option<&'a Type>,
+    boxed: bool,
+    snake: String,
+}
+
+/// Whether a variant is marked `#[word_enum(boxed)]`.
+fn is_boxed(variant: &syn::Variant) -> syn::Result<bool> {
+    let mut boxed = false;
+    for attr in &variant.attrs {
+        if !attr.path().is_ident("word_enum") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("boxed") {
+                boxed = true;
+                Ok(())
+            } else {
+                Err(meta.error("unknown word_enum attribute"))
+            }
+        })?;
+    }
+    if boxed && variant.fields.is_empty() {
+        return Err(syn::Error::new_spanned(
+            variant,
+            "only variants with a field can be marked #[word_enum(boxed)]",
+        ));
+    }
+    Ok(boxed)
+}
+
+fn word_enum(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let data = match input.data {
+        Data::Enum(ref data) => data,
+        _ => return Err(syn::Error::new_spanned(input, "WordEnum can only be derived for enums")),
+    };
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "WordEnum can't be derived for generic enums",
+        ));
+    }
+    if data.variants.is_empty() {
+        return Err(syn::Error::new_spanned(input, "WordEnum can't be derived for empty enums"));
+    }
+
+    let mut variants = Vec::new();
+    for variant in &data.variants {
+        let field = match variant.fields {
+            Fields::Unit => None,
+            Fields::Unnamed(ref fields) if fields.unnamed.len() == 1 => Some(&fields.unnamed[0].ty),
+            _ => return Err(syn::Error::new_spanned(
+                variant,
+                "WordEnum variants must have no fields or a single unnamed field",
+            )),
+        };
+        variants.push(Variant {
+            ident: &variant.ident,
+            field,
+            boxed: is_boxed(variant)?,
+            snake: snake_case(&variant.ident.to_string()),
+        });
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let word = Ident::new(&format!("{}Word", name), Span::call_site());
+    let count = variants.len();
+    let bits = (usize::BITS - (count - 1).leading_zeros()).max(1);
+    let raw_word = quote!(::maybe_box::word_enum::RawWord<#name, #bits>);
+    let doc = format!("A `{}` packed into the space for a `usize`.", name);
+    let too_many = format!(
+        "`{}` has {} variants, which is more than fit in the spare bits of a word",
+        name, count,
+    );
+
+    // Each field is stored in a `RawWord<#name, #bits>`, so `RawWord::new` is sound.
+    let pack_arms = variants.iter().enumerate().map(|(tag, v)| {
+        let ident = v.ident;
+        let new = quote!(::maybe_box::word_enum::RawWord::new);
+        match v.field {
+            Some(_) => quote!(#name::#ident(x) => unsafe { #new(x, #tag) }),
+            None => quote!(#name::#ident => unsafe { #new((), #tag) }),
+        }
+    });
+    let unpack_arms = variants.iter().enumerate().map(|(tag, v)| {
+        let ident = v.ident;
+        match v.field {
+            Some(ty) => quote!(#tag => #name::#ident(raw.take::<#ty>())),
+            None => quote!(#tag => #name::#ident),
+        }
+    });
+    let raw_arms = variants.iter().enumerate().map(|(tag, v)| match v.field {
+        Some(ty) if v.boxed => quote!(#tag => raw.into_boxed_raw::<#ty>()),
+        Some(ty) => quote!(#tag => raw.into_raw::<#ty>()),
+        None => quote!(#tag => raw.into_raw::<()>()),
+    });
+    // The bounds are higher-ranked so that they're only checked where `into_raw` is called, rather
+    // than failing to compile the whole impl for enums with fields that aren't `NoUninit`. Boxed
+    // fields are never part of the word, so they don't need them.
+    let no_uninit = variants.iter().filter(|v| !v.boxed).filter_map(|v| v.field).map(|ty| {
+        quote!(for<'a> #ty: ::maybe_box::NoUninit)
+    });
+    let no_uninit = quote!(#(#no_uninit,)*);
+    let drop_arms = variants.iter().enumerate().filter_map(|(tag, v)| {
+        v.field.map(|ty| quote!(#tag => unsafe { self.raw.drop_in_place::<#ty>() }))
+    });
+    let methods = variants.iter().enumerate().map(|(tag, v)| {
+        let is = Ident::new(&format!("is_{}", v.snake), Span::call_site());
+        let is_doc = format!("Whether this holds a `{}::{}`.", name, v.ident);
+        let is = quote! {
+            #[doc = #is_doc]
+            pub fn #is(&self) -> bool {
+                self.raw.tag() == #tag
+            }
+        };
+        let ty = match v.field {
+            Some(ty) => ty,
+            None => return is,
+        };
+        let as_ = Ident::new(&format!("as_{}", v.snake), Span::call_site());
+        let as_mut = Ident::new(&format!("as_{}_mut", v.snake), Span::call_site());
+        let as_doc = format!("Borrow the field of a `{}::{}`.", name, v.ident);
+        let as_mut_doc = format!("Mutably borrow the field of a `{}::{}`.", name, v.ident);
+        quote! {
+            #is
+
+            #[doc = #as_doc]
+            pub fn #as_(&self) -> ::std::option::Option<&#ty> {
+                if self.raw.tag() == #tag {
+                    ::std::option::Option::Some(unsafe { self.raw.get::<#ty>() })
+                } else {
+                    ::std::option::Option::None
+                }
+            }
+
+            #[doc = #as_mut_doc]
+            pub fn #as_mut(&mut self) -> ::std::option::Option<&mut #ty> {
+                if self.raw.tag() == #tag {
+                    ::std::option::Option::Some(unsafe { self.raw.get_mut::<#ty>() })
+                } else {
+                    ::std::option::Option::None
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[repr(transparent)]
+        #vis struct #word {
+            raw: #raw_word,
+        }
+
+        const _: () = assert!(#count <= ::maybe_box::word_enum::MAX_VARIANTS, #too_many);
+
+        impl ::maybe_box::word_enum::WordEnum for #name {
+            type Packed = #word;
+
+            fn pack(self) -> #word {
+                #word {
+                    raw: match self {
+                        #(#pack_arms,)*
+                    },
+                }
+            }
+
+            fn unpack(packed: #word) -> #name {
+                let packed = ::std::mem::ManuallyDrop::new(packed);
+                unsafe {
+                    let raw: #raw_word = ::std::ptr::read(&packed.raw);
+                    match raw.tag() {
+                        #(#unpack_arms,)*
+                        _ => ::std::unreachable!(),
+                    }
+                }
+            }
+        }
+
+        #[allow(dead_code)]
+        impl #word {
+            /// Recover the enum from its packed representation.
+            pub fn unpack(self) -> #name {
+                <#name as ::maybe_box::word_enum::WordEnum>::unpack(self)
+            }
+
+            #(#methods)*
+
+            /// Consume the packed enum and return the word it's stored in, for passing to C code as
+            /// a `void *`. This requires each variant's field to implement `NoUninit`, unless the
+            /// variant is marked `#[word_enum(boxed)]`.
+            ///
+            /// The word must eventually be turned back into the packed enum with `from_raw`. See
+            /// the `maybe_box` crate docs on raw words.
+            pub fn into_raw(self) -> *mut ::std::os::raw::c_void
+            where
+                #no_uninit
+            {
+                let this = ::std::mem::ManuallyDrop::new(self);
+                unsafe {
+                    let raw: #raw_word = ::std::ptr::read(&this.raw);
+                    match raw.tag() {
+                        #(#raw_arms,)*
+                        _ => ::std::unreachable!(),
+                    }
+                }
+            }
+
+            /// Reconstruct the packed enum from a word returned by `into_raw`.
+            ///
+            /// # Safety
+            ///
+            /// `raw` must have been returned by `into_raw`, and not already turned back into a
+            /// packed enum.
+            pub unsafe fn from_raw(raw: *mut ::std::os::raw::c_void) -> #word
+            where
+                #no_uninit
+            {
+                #word {
+                    raw: ::maybe_box::word_enum::RawWord::from_raw(raw),
+                }
+            }
+        }
+
+        impl ::std::ops::Drop for #word {
+            fn drop(&mut self) {
+                match self.raw.tag() {
+                    #(#drop_arms,)*
+                    _ => (),
+                }
+            }
+        }
+
+        impl ::std::convert::From<#name> for #word {
+            fn from(e: #name) -> #word {
+                ::maybe_box::word_enum::WordEnum::pack(e)
+            }
+        }
+    })
+}
+
+/// Convert a variant name from `CamelCase` to `snake_case`.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut ret = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_lower) {
+                ret.push('_');
+            }
+        }
+        ret.extend(c.to_lowercase());
+    }
+    ret
+}
@@ -0,0 +1,278 @@
+//! Boxing the values in a `MaybeBox` with a custom allocator.
+//!
+//! This uses the `Allocator` trait from the `allocator-api2` crate, which mirrors the unstable
+//! `std::alloc::Allocator` and works on stable Rust. A `MaybeBox<T, A>` is created with
+//! `MaybeBox::new_in`. Values that fit inline never touch the allocator. Values that need boxing
+//! are allocated with it, eg. in an arena or a per-subsystem allocator, and `unpack_in` returns
+//! them in a `Box<T, A>`.
+//!
+//! The allocator is stored alongside the word, so `MaybeBox<T, A>` is one word only if `A` is
+//! zero-sized. This includes the default, `Global`, so `MaybeBox<T>` is still the same size as a
+//! `usize`. An allocator such as `&Bump` adds a second word.
+
+use std::alloc::Layout;
+use std::mem::MaybeUninit;
+use std::os::raw::c_void;
+use std::ptr;
+
+pub use allocator_api2::alloc::{Allocator, Global};
+pub use allocator_api2::boxed::Box;
+
+use {data_ptr, fits_inline, is_zst, read_raw, read_boxed_raw, data_from_raw};
+use {AllocError, Carrier, MaybeBox, MaybeBoxIn, NoUninit};
+
+/// An unpacked `MaybeBoxIn<T, C, A>`. Produced by `MaybeBoxIn::unpack_in`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AllocUnpacked<T, A: Allocator = Global> {
+    /// A `T` stored inline, along with the allocator it wasn't needed from. Zero-sized types are
+    /// always unpacked as `Inline`.
+    Inline(T, A),
+    /// A `T` stored in a `Box<T, A>`.
+    Boxed(Box<T, A>),
+}
+
+impl<T, C: Carrier, A: Allocator> MaybeBoxIn<T, C, A> {
+    /// Wrap a `T` into a `MaybeBoxIn<T, C, A>`. This will allocate from `alloc` if a `T` doesn't
+    /// fit inline, the same as `new` does from the global allocator. Zero-sized types never
+    /// allocate.
+    #[inline]
+    pub fn new_in(t: T, alloc: A) -> MaybeBoxIn<T, C, A> {
+        if fits_inline::<T, C>() {
+            let data = MaybeBoxIn::<T, C>::new(t).into_data();
+            return unsafe { MaybeBoxIn::from_data_in(data, alloc) };
+        }
+        let () = MaybeBoxIn::<T, C>::CAN_HOLD;
+        let (ptr, alloc) = Box::into_raw_with_allocator(Box::new_in(t, alloc));
+        unsafe { MaybeBoxIn::from_box_ptr_in(ptr, alloc) }
+    }
+
+    /// Wrap a `T` into a `MaybeBoxIn<T, C, A>` like `new_in`, but if boxing it fails to allocate
+    /// from `alloc` then return `t` along with an error rather than aborting.
+    pub fn try_new_in(t: T, alloc: A) -> Result<MaybeBoxIn<T, C, A>, (T, AllocError)> {
+        if fits_inline::<T, C>() {
+            return Ok(MaybeBoxIn::new_in(t, alloc));
+        }
+        let () = MaybeBoxIn::<T, C>::CAN_HOLD;
+        let ptr = match alloc.allocate(Layout::new::<T>()) {
+            Ok(ptr) => ptr.as_ptr() as *mut T,
+            Err(_) => return Err((t, AllocError)),
+        };
+        unsafe {
+            ptr::write(ptr, t);
+            Ok(MaybeBoxIn::from_box_ptr_in(ptr, alloc))
+        }
+    }
+
+    /// Make a `MaybeBoxIn<T, C, A>` holding a boxed `T` from a pointer to its allocation, which
+    /// was allocated with `alloc`.
+    #[inline]
+    unsafe fn from_box_ptr_in(ptr: *mut T, alloc: A) -> MaybeBoxIn<T, C, A> {
+        let mut data = MaybeUninit::zeroed();
+        ptr::write(data.as_mut_ptr() as *mut *mut T, ptr);
+        MaybeBoxIn::from_data_in(data, alloc)
+    }
+
+    /// Get a reference to the allocator.
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    /// Consume the `MaybeBoxIn<T, C, A>` and return the inner `T`, possibly boxed (if it was
+    /// already), along with the allocator if it wasn't.
+    pub fn unpack_in(self) -> AllocUnpacked<T, A> {
+        let (data, alloc) = self.into_data_in();
+        unsafe {
+            if fits_inline::<T, C>() {
+                AllocUnpacked::Inline(ptr::read(data_ptr::<T, C>(&data)), alloc)
+            } else {
+                let ptr = ptr::read(data.as_ptr() as *const *mut T);
+                AllocUnpacked::Boxed(Box::from_raw_in(ptr, alloc))
+            }
+        }
+    }
+}
+
+impl<T: NoUninit, A: Allocator> MaybeBox<T, A> {
+    /// Consume the `MaybeBox<T, A>` and return the word it's stored in, for passing to C code as a
+    /// `void *`, along with the allocator. The word is the same as `into_raw` would return.
+    ///
+    /// The word must eventually be turned back into a `MaybeBox<T, A>` with `from_raw_in`. See the
+    /// crate docs on raw words.
+    pub fn into_raw_with_allocator(self) -> (*mut c_void, A) {
+        let (data, alloc) = self.into_data_in();
+        let raw = unsafe { read_raw::<T, _>(data.as_ptr() as *const *mut c_void) };
+        (raw, alloc)
+    }
+}
+
+impl<T, A: Allocator> MaybeBox<T, A> {
+    /// The same as `into_raw_with_allocator`, for a `T` that's too big to be stored inline, so
+    /// that it doesn't need to implement `NoUninit`. Fails to compile if a `T` would be stored
+    /// inline.
+    pub fn into_boxed_raw_with_allocator(self) -> (*mut c_void, A) {
+        const {
+            assert!(
+                is_zst::<T>() || !fits_inline::<T, usize>(),
+                "value is stored inline, so `into_boxed_raw_with_allocator` can't be used",
+            )
+        };
+        let (data, alloc) = self.into_data_in();
+        let raw = unsafe { read_boxed_raw(data.as_ptr() as *const *mut c_void) };
+        (raw, alloc)
+    }
+
+    /// Reconstruct a `MaybeBox<T, A>` from a word returned by `into_raw_with_allocator` or
+    /// `into_boxed_raw_with_allocator` and the allocator returned with it.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBox::<T, A>::into_raw_with_allocator` or
+    /// `MaybeBox::<T, A>::into_boxed_raw_with_allocator` for the same `T` and `A`, and not already
+    /// turned back into a `MaybeBox<T, A>`. If the value is boxed then `alloc` must be able to
+    /// free it.
+    pub unsafe fn from_raw_in(raw: *mut c_void, alloc: A) -> MaybeBox<T, A> {
+        MaybeBoxIn::from_data_in(data_from_raw(raw), alloc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use allocator_api2::alloc::{AllocError, Layout};
+    use std::cell::Cell;
+    use std::mem;
+    use std::ptr::NonNull;
+    use std::rc::Rc;
+
+    /// Counts the allocations that are currently live.
+    #[derive(Default)]
+    struct Counting {
+        live: Cell<usize>,
+    }
+
+    unsafe impl Allocator for Counting {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.live.set(self.live.get() + 1);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.live.set(self.live.get() - 1);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn custom_allocator() {
+        assert_eq!(mem::size_of::<MaybeBox<[u8; 100]>>(), mem::size_of::<usize>());
+
+        let counting = Counting::default();
+        let mb = MaybeBox::new_in(3u8, &counting);
+        assert_eq!(counting.live.get(), 0);
+        match mb.unpack_in() {
+            AllocUnpacked::Inline(3, _) => (),
+            _ => panic!("expected inline"),
+        }
+
+        let mut mb = MaybeBox::new_in([1u64; 4], &counting);
+        assert_eq!(counting.live.get(), 1);
+        mb[3] = 2;
+        let (raw, alloc) = mb.into_raw_with_allocator();
+        let mb = unsafe { MaybeBox::<[u64; 4], _>::from_raw_in(raw, alloc) };
+        match mb.unpack_in() {
+            AllocUnpacked::Boxed(b) => {
+                assert_eq!(*b, [1, 1, 1, 2]);
+                assert_eq!(counting.live.get(), 1);
+            },
+            _ => panic!("expected boxed"),
+        }
+        assert_eq!(counting.live.get(), 0);
+        assert_eq!(MaybeBox::<_>::from([5u8; 64]).into_inner(), [5; 64]);
+        assert_eq!(mem::size_of::<MaybeBox<u8, &Counting>>(), 2 * mem::size_of::<usize>());
+
+        let mb = MaybeBox::new_in([2u64; 4], &counting);
+        assert_eq!(counting.live.get(), 1);
+        assert_eq!(mb.into_inner(), [2; 4]);
+        assert_eq!(counting.live.get(), 0);
+    }
+
+    #[test]
+    fn traits() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        fn hash<T: Hash>(t: &T) -> u64 {
+            let mut h = DefaultHasher::new();
+            t.hash(&mut h);
+            h.finish()
+        }
+
+        let counting = Counting::default();
+        let a = MaybeBox::new_in(String::from("hello"), &counting);
+        let b = MaybeBox::new(String::from("hello"));
+        assert!(a == b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_eq!(hash(&a), hash(&String::from("hello")));
+        assert!(MaybeBox::new(1u8) != MaybeBox::new(2u8));
+        assert_eq!(format!("{:?}", a), "MaybeBox(\"hello\")");
+    }
+
+    #[test]
+    fn try_new() {
+        /// Fails every allocation.
+        struct Failing;
+
+        unsafe impl Allocator for Failing {
+            fn allocate(&self, _: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                Err(AllocError)
+            }
+
+            unsafe fn deallocate(&self, _: NonNull<u8>, _: Layout) {
+                unreachable!()
+            }
+        }
+
+        let mb = MaybeBox::try_new_in(5u32, Failing).unwrap();
+        assert_eq!(*mb, 5);
+        match MaybeBox::try_new_in([1u64; 4], Failing) {
+            Err((t, ::AllocError)) => assert_eq!(t, [1; 4]),
+            Ok(_) => panic!("expected an error"),
+        }
+
+        let counting = Counting::default();
+        let mb = MaybeBox::try_new_in([1u64; 4], &counting).unwrap();
+        assert_eq!(counting.live.get(), 1);
+        assert_eq!(mb.into_inner(), [1; 4]);
+        assert_eq!(counting.live.get(), 0);
+        assert_eq!(*MaybeBox::try_new(String::from("hi")).unwrap(), "hi");
+    }
+
+    #[test]
+    fn drops() {
+        let counting = Counting::default();
+        let rc = Rc::new(());
+        let mb = MaybeBox::new_in((rc.clone(), [0u8; 64]), &counting);
+        let small = MaybeBox::new_in(rc.clone(), &counting);
+        assert_eq!(Rc::strong_count(&rc), 3);
+        drop((mb, small));
+        assert_eq!(Rc::strong_count(&rc), 1);
+        assert_eq!(counting.live.get(), 0);
+
+        let mb = MaybeBox::new_in((rc.clone(), [0u8; 64]), &counting);
+        let (raw, alloc) = mb.into_boxed_raw_with_allocator();
+        let mb = unsafe { MaybeBox::<(Rc<()>, [u8; 64]), _>::from_raw_in(raw, alloc) };
+        assert_eq!(Rc::strong_count(&rc), 2);
+        assert_eq!(counting.live.get(), 1);
+        drop(mb);
+        assert_eq!(Rc::strong_count(&rc), 1);
+        assert_eq!(counting.live.get(), 0);
+
+        // These have uninitialized bytes, which mustn't be read as part of a word.
+        drop(MaybeBox::new_in((1u8, 2u16), &counting));
+        let unpacked = MaybeBox::new_in(Some(3u16), &counting).unpack_in();
+        match unpacked {
+            AllocUnpacked::Inline(Some(3), _) => (),
+            _ => panic!("expected inline"),
+        }
+    }
+}
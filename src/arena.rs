@@ -0,0 +1,416 @@
+//! A `MaybeBox` which boxes values in a bump arena.
+//!
+//! An `ArenaMaybeBox<'arena, T>` stores its value inline when it fits in a `usize`, just like a
+//! `MaybeBox<T>`, and otherwise allocates it in an `Arena`, which wraps a `bumpalo::Bump`. The
+//! handle borrows the arena, so it can't outlive it, and the memory of its value is only reclaimed
+//! when the arena is reset or dropped, all at once.
+//!
+//! The `DropPolicy` of the arena decides when the destructors of boxed values run. With
+//! `DropPolicy::OnHandleDrop` a boxed value is dropped along with its handle, as with `MaybeBox`.
+//! With `DropPolicy::OnReset` dropping the handle does nothing and the arena drops the value when
+//! it's reset or dropped, so even handles that were leaked get their values dropped. Values that
+//! are stored inline are always dropped along with their handle.
+
+use std::alloc::Layout;
+use std::cell::RefCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::ptr;
+
+use bumpalo::Bump;
+use bumpalo::boxed::Box;
+
+use {data_ptr, data_ptr_mut, fits_inline, is_zst, write_data, read_raw, read_boxed_raw, data_from_raw};
+use NoUninit;
+
+/// When the destructors of values boxed in an `Arena` run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// A boxed value is dropped when its `ArenaMaybeBox` is dropped.
+    OnHandleDrop,
+    /// A boxed value is dropped when the arena is reset or dropped. Values are dropped in the
+    /// reverse of the order they were created in.
+    OnReset,
+}
+
+/// A bump arena for `ArenaMaybeBox`es to box their values in.
+///
+/// Values boxed in an `Arena<'a>` can borrow data for `'a`, which must outlive the arena, since
+/// the arena may drop them:
+///
+/// ```compile_fail
+/// # use maybe_box::arena::{Arena, ArenaMaybeBox, DropPolicy};
+/// let arena = Arena::new(DropPolicy::OnReset);
+/// {
+///     let s = String::from("dropped before the arena");
+///     ArenaMaybeBox::new_in([&s; 4], &arena);
+/// }
+/// ```
+pub struct Arena<'a> {
+    bump: Bump,
+    policy: DropPolicy,
+    /// The values to drop when the arena is reset, if the policy is `OnReset`.
+    pending: RefCell<Vec<Pending>>,
+    // Invariant, so that the arena can't be treated as holding values that borrow for less than
+    // `'a`.
+    _ph: PhantomData<fn(&'a ()) -> &'a ()>,
+}
+
+/// A boxed value that the arena is responsible for dropping.
+struct Pending {
+    slot: *mut u8,
+    drop: unsafe fn(*mut u8),
+}
+
+/// The allocation in the arena for a boxed `T`.
+#[repr(C)]
+struct Slot<T> {
+    value: T,
+    /// Cleared if the value is moved out before the arena drops it.
+    live: bool,
+}
+
+/// Drop the value in a `Slot<T>`, if it hasn't been moved out.
+unsafe fn drop_slot<T>(slot: *mut u8) {
+    let slot = slot as *mut Slot<T>;
+    if (*slot).live {
+        ptr::drop_in_place(ptr::addr_of_mut!((*slot).value));
+    }
+}
+
+impl<'a> Arena<'a> {
+    /// Create an empty arena with the given drop policy.
+    pub fn new(policy: DropPolicy) -> Arena<'a> {
+        Arena::from_bump(Bump::new(), policy)
+    }
+
+    /// Create an arena which allocates from `bump`, with the given drop policy.
+    pub fn from_bump(bump: Bump, policy: DropPolicy) -> Arena<'a> {
+        Arena {
+            bump,
+            policy,
+            pending: RefCell::new(Vec::new()),
+            _ph: PhantomData,
+        }
+    }
+
+    /// Get the drop policy.
+    pub fn policy(&self) -> DropPolicy {
+        self.policy
+    }
+
+    /// Get the underlying bump allocator.
+    pub fn bump(&self) -> &Bump {
+        &self.bump
+    }
+
+    /// Drop the values that are waiting to be dropped.
+    fn drop_pending(&mut self) {
+        let pending = self.pending.get_mut();
+        while let Some(p) = pending.pop() {
+            unsafe { (p.drop)(p.slot) };
+        }
+    }
+
+    /// Drop any values waiting to be dropped, then free everything allocated in the arena so that
+    /// its memory can be reused.
+    pub fn reset(&mut self) {
+        self.drop_pending();
+        self.bump.reset();
+    }
+}
+
+impl<'a> Drop for Arena<'a> {
+    fn drop(&mut self) {
+        self.drop_pending();
+    }
+}
+
+impl<'a> fmt::Debug for Arena<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Arena")
+            .field("allocated_bytes", &self.bump.allocated_bytes())
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// Hold a value of type `T` in the space for a `usize`, only boxing it in an `Arena` if
+/// necessary.
+///
+/// If the value is boxed then the lowest bit of the word is set if the arena, rather than the
+/// handle, is responsible for dropping it.
+///
+/// This type is guaranteed to be the same size as a `usize`.
+#[repr(transparent)]
+pub struct ArenaMaybeBox<'arena, T> {
+    data: MaybeUninit<usize>,
+    _ph: PhantomData<(&'arena Bump, T)>,
+}
+
+/// An unpacked `ArenaMaybeBox<'arena, T>`. Produced by `ArenaMaybeBox::unpack`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Unpacked<'arena, T> {
+    /// A `T` stored inline. Zero-sized types are always unpacked as `Inline`.
+    Inline(T),
+    /// A `T` stored in the arena. Dropping the `Box` drops the `T` but doesn't free its memory.
+    Boxed(Box<'arena, T>),
+}
+
+impl<'arena, T> ArenaMaybeBox<'arena, T> {
+    /// The layout of a boxed `T`'s slot, which leaves the lowest bit of the pointer free.
+    #[inline]
+    fn slot_layout() -> Layout {
+        match Layout::new::<Slot<T>>().align_to(2) {
+            Ok(layout) => layout,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Wrap a `T` into an `ArenaMaybeBox<'arena, T>`. This will allocate in `arena` if
+    /// `size_of::<T>() > size_of::<usize>()` or if `T` requires a greater alignment than `usize`.
+    /// Zero-sized types never allocate.
+    pub fn new_in<'a>(t: T, arena: &'arena Arena<'a>) -> ArenaMaybeBox<'arena, T>
+        where T: 'a
+    {
+        let mut data = MaybeUninit::zeroed();
+        unsafe {
+            if fits_inline::<T, usize>() {
+                write_data(t, &mut data);
+            } else {
+                let slot = arena.bump.alloc_layout(ArenaMaybeBox::<T>::slot_layout());
+                let slot = slot.as_ptr() as *mut Slot<T>;
+                ptr::write(slot, Slot { value: t, live: true });
+                let deferred = arena.policy == DropPolicy::OnReset;
+                if deferred {
+                    arena.pending.borrow_mut().push(Pending {
+                        slot: slot as *mut u8,
+                        drop: drop_slot::<T>,
+                    });
+                }
+                let word = slot.map_addr(|addr| addr | deferred as usize);
+                ptr::write(data.as_mut_ptr() as *mut *mut Slot<T>, word);
+            }
+        }
+        ArenaMaybeBox {
+            data,
+            _ph: PhantomData,
+        }
+    }
+
+    /// Whether the arena is responsible for dropping the boxed value.
+    #[inline]
+    fn deferred(&self) -> bool {
+        if fits_inline::<T, usize>() {
+            return false;
+        }
+        let word = unsafe { ptr::read(self.data.as_ptr() as *const *mut u8) };
+        word.addr() & 1 == 1
+    }
+
+    /// Get a pointer to the slot of a boxed value.
+    #[inline]
+    unsafe fn slot_ptr(data: *const MaybeUninit<usize>) -> *mut Slot<T> {
+        ptr::read(data as *const *mut Slot<T>).map_addr(|addr| addr & !1)
+    }
+
+    /// Consume the `ArenaMaybeBox<'arena, T>` and return the inner `T`.
+    pub fn into_inner(self) -> T {
+        match self.unpack() {
+            Unpacked::Inline(t) => t,
+            Unpacked::Boxed(b) => Box::into_inner(b),
+        }
+    }
+
+    /// Consume the `ArenaMaybeBox<'arena, T>` and return the inner `T`, possibly boxed in the arena
+    /// (if it was already).
+    pub fn unpack(self) -> Unpacked<'arena, T> {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            if fits_inline::<T, usize>() {
+                return Unpacked::Inline(ptr::read(data_ptr::<T, usize>(&this.data)));
+            }
+            let slot = ArenaMaybeBox::<T>::slot_ptr(&this.data);
+            // The `Box` takes over dropping the value from the arena.
+            (*slot).live = false;
+            Unpacked::Boxed(Box::from_raw(ptr::addr_of_mut!((*slot).value)))
+        }
+    }
+}
+
+impl<'arena, T: NoUninit> ArenaMaybeBox<'arena, T> {
+    /// Consume the `ArenaMaybeBox<'arena, T>` and return the word it's stored in, for passing to C
+    /// code as a `void *`.
+    ///
+    /// The word must eventually be turned back into an `ArenaMaybeBox<'arena, T>` with
+    /// `from_raw`. See the crate docs on raw words.
+    pub fn into_raw(self) -> *mut c_void {
+        let this = ManuallyDrop::new(self);
+        unsafe { read_raw::<T, _>(this.data.as_ptr() as *const *mut c_void) }
+    }
+}
+
+impl<'arena, T> ArenaMaybeBox<'arena, T> {
+    /// The same as `into_raw`, for a `T` that's too big to be stored inline, so that it doesn't
+    /// need to implement `NoUninit`. Fails to compile if a `T` would be stored inline.
+    pub fn into_boxed_raw(self) -> *mut c_void {
+        const {
+            assert!(
+                is_zst::<T>() || !fits_inline::<T, usize>(),
+                "value is stored inline, so `into_boxed_raw` can't be used",
+            )
+        };
+        let this = ManuallyDrop::new(self);
+        unsafe { read_boxed_raw(this.data.as_ptr() as *const *mut c_void) }
+    }
+
+    /// Reconstruct an `ArenaMaybeBox<'arena, T>` from a word returned by `into_raw` or
+    /// `into_boxed_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `ArenaMaybeBox::<'arena, T>::into_raw` or
+    /// `ArenaMaybeBox::<'arena, T>::into_boxed_raw` for the same `T` and an arena which is still
+    /// borrowed for `'arena`, and not already turned back into an `ArenaMaybeBox<'arena, T>`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> ArenaMaybeBox<'arena, T> {
+        ArenaMaybeBox {
+            data: data_from_raw(raw),
+            _ph: PhantomData,
+        }
+    }
+}
+
+impl<'arena, T> Drop for ArenaMaybeBox<'arena, T> {
+    fn drop(&mut self) {
+        unsafe {
+            if fits_inline::<T, usize>() {
+                ptr::drop_in_place(data_ptr_mut::<T, usize>(&mut self.data));
+            } else if !self.deferred() {
+                let slot = ArenaMaybeBox::<T>::slot_ptr(&self.data);
+                ptr::drop_in_place(ptr::addr_of_mut!((*slot).value));
+            }
+        }
+    }
+}
+
+impl<'arena, T> Deref for ArenaMaybeBox<'arena, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe {
+            if fits_inline::<T, usize>() {
+                &*data_ptr::<T, usize>(&self.data)
+            } else {
+                &(*ArenaMaybeBox::<T>::slot_ptr(&self.data)).value
+            }
+        }
+    }
+}
+
+impl<'arena, T> DerefMut for ArenaMaybeBox<'arena, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {
+            if fits_inline::<T, usize>() {
+                &mut *data_ptr_mut::<T, usize>(&mut self.data)
+            } else {
+                &mut (*ArenaMaybeBox::<T>::slot_ptr(&self.data)).value
+            }
+        }
+    }
+}
+
+impl<'arena, T: fmt::Debug> fmt::Debug for ArenaMaybeBox<'arena, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner: &T = self;
+        f.debug_tuple("ArenaMaybeBox").field(inner).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::mem;
+    use std::rc::Rc;
+
+    #[test]
+    fn inline_and_boxed() {
+        assert_eq!(mem::size_of::<ArenaMaybeBox<[u8; 100]>>(), mem::size_of::<usize>());
+
+        let arena = Arena::new(DropPolicy::OnHandleDrop);
+        let mb = ArenaMaybeBox::new_in(7u32, &arena);
+        assert_eq!(arena.bump().allocated_bytes_including_metadata(), 0);
+        match mb.unpack() {
+            Unpacked::Inline(7) => (),
+            _ => panic!("expected inline"),
+        }
+
+        let mut mb = ArenaMaybeBox::new_in([1u64; 4], &arena);
+        mb[3] = 2;
+        assert_eq!(format!("{:?}", mb), "ArenaMaybeBox([1, 1, 1, 2])");
+        let raw = mb.into_raw();
+        assert_eq!(raw as usize & 1, 0);
+        let mb = unsafe { ArenaMaybeBox::<[u64; 4]>::from_raw(raw) };
+        match mb.unpack() {
+            Unpacked::Boxed(b) => assert_eq!(*b, [1, 1, 1, 2]),
+            _ => panic!("expected boxed"),
+        }
+        assert_eq!(ArenaMaybeBox::new_in(String::from("hello"), &arena).into_inner(), "hello");
+    }
+
+    /// Counts how many times it's dropped.
+    struct Counted<'a>(&'a Cell<usize>, [u8; 64]);
+
+    impl<'a> Drop for Counted<'a> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn drop_on_handle_drop() {
+        let drops = Cell::new(0);
+        let mut arena = Arena::new(DropPolicy::OnHandleDrop);
+        let mb = ArenaMaybeBox::new_in(Counted(&drops, [0; 64]), &arena);
+        let rc = Rc::new(());
+        let small = ArenaMaybeBox::new_in(rc.clone(), &arena);
+        drop((mb, small));
+        assert_eq!(drops.get(), 1);
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let raw = ArenaMaybeBox::new_in(Counted(&drops, [2; 64]), &arena).into_boxed_raw();
+        let mb = unsafe { ArenaMaybeBox::<Counted>::from_raw(raw) };
+        assert_eq!(mb.1, [2; 64]);
+        drop(mb);
+        assert_eq!(drops.get(), 2);
+
+        mem::forget(ArenaMaybeBox::new_in(Counted(&drops, [1; 64]), &arena));
+        arena.reset();
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn drop_on_reset() {
+        let drops = Cell::new(0);
+        let mut arena = Arena::new(DropPolicy::OnReset);
+        mem::forget(ArenaMaybeBox::new_in(Counted(&drops, [0; 64]), &arena));
+        let raw = ArenaMaybeBox::new_in([4u64; 8], &arena).into_raw();
+        assert_eq!(raw as usize & 1, 1);
+        drop(unsafe { ArenaMaybeBox::<[u64; 8]>::from_raw(raw) });
+        let taken = ArenaMaybeBox::new_in(Counted(&drops, [1; 64]), &arena).into_inner();
+        drop(ArenaMaybeBox::new_in(Counted(&drops, [2; 64]), &arena));
+        assert_eq!(drops.get(), 0);
+        assert_eq!(taken.1, [1; 64]);
+
+        arena.reset();
+        assert_eq!(drops.get(), 2);
+        drop(taken);
+        assert_eq!(drops.get(), 3);
+        ArenaMaybeBox::new_in(Counted(&drops, [3; 64]), &arena);
+        drop(arena);
+        assert_eq!(drops.get(), 4);
+    }
+}
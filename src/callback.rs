@@ -0,0 +1,379 @@
+//! Trampolines for handing Rust closures to C code as a function pointer and a `void *`.
+//!
+//! C APIs that accept a callback usually take a function pointer along with a `void *` userdata
+//! word which is passed back to the function whenever it's called. The functions in this module
+//! store a closure in a `MaybeBox` and return a `RawCallback` holding an `extern "C"` trampoline
+//! which recovers the closure from the userdata and calls it. Trampolines take the userdata as
+//! their first argument, followed by the closure's arguments.
+//!
+//! Closures are always boxed, since a closure's captures may include padding bytes, which can't be
+//! passed to C as part of a `void *`. Boxing also means that changes a closure makes to its own
+//! captures persist between calls, which they wouldn't if the closure were a copy made from the
+//! word C passes back. Closures that capture nothing are zero-sized though, so
+//! converting them doesn't allocate. To avoid allocating for a closure that outlives the callback,
+//! such as one on the stack for the duration of a blocking C call, convert a borrow of it with
+//! `fn_mut_ref_N` or `fn_ref_N` instead. The reference is stored inline in the userdata word.
+//!
+//! Panics are not allowed to unwind into C. If a closure panics, the trampoline catches the panic,
+//! returns `R::default()` and stashes the panic payload in a thread-local so that it can be
+//! re-raised with `resume_panic` once control has returned to Rust.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::os::raw::c_void;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+
+use MaybeBox;
+
+/// A Rust closure converted into a form that can be handed to C.
+#[derive(Debug, Clone, Copy)]
+pub struct RawCallback<Func> {
+    /// The trampoline to pass to C as the callback.
+    pub func: Func,
+    /// The userdata word to pass to C alongside `func`.
+    pub data: *mut c_void,
+    /// Frees `data`. This should be passed to C if the API accepts a destructor, or called once
+    /// C is done with the callback.
+    pub drop: unsafe extern "C" fn(*mut c_void),
+}
+
+thread_local! {
+    static PANIC: RefCell<Option<Box<dyn Any + Send>>> = const { RefCell::new(None) };
+}
+
+/// Call `f`, catching any panic and storing it in `PANIC`. Only the first panic is kept if
+/// several occur before it's taken.
+fn catch<R: Default, G: FnOnce() -> R>(f: G) -> R {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(r) => r,
+        Err(payload) => {
+            PANIC.with(|p| {
+                let mut p = p.borrow_mut();
+                if p.is_none() {
+                    *p = Some(payload);
+                }
+            });
+            R::default()
+        },
+    }
+}
+
+/// Take the payload of a panic that was caught in a callback on this thread, if there was one.
+pub fn take_panic() -> Option<Box<dyn Any + Send>> {
+    PANIC.with(|p| p.borrow_mut().take())
+}
+
+/// If a callback on this thread has panicked since the last call to `take_panic` or
+/// `resume_panic`, resume unwinding with the panic's payload.
+pub fn resume_panic() {
+    if let Some(payload) = take_panic() {
+        panic::resume_unwind(payload);
+    }
+}
+
+macro_rules! callbacks {
+    ($(
+        $fn_mut:ident, $fn_mut_ref:ident, $fn_mut_trampoline:ident,
+        $fn_:ident, $fn_ref:ident, $fn_trampoline:ident,
+        $fn_once:ident, $fn_once_trampoline:ident,
+        ($($arg:ident: $A:ident),*);
+    )*) => {$(
+        /// Convert an `FnMut` closure into a C callback. This allocates unless the closure is
+        /// zero-sized.
+        ///
+        /// The trampoline must not be called re-entrantly, nor after `drop` has been called or
+        /// any borrows captured by the closure have expired.
+        pub fn $fn_mut<F, $($A,)* R>(f: F)
+            -> RawCallback<unsafe extern "C" fn(*mut c_void $(, $A)*) -> R>
+            where F: FnMut($($A),*) -> R,
+                  R: Default
+        {
+            let (data, drop) = MaybeBox::new(Box::new(f)).into_raw_with_drop();
+            RawCallback {
+                func: $fn_mut_trampoline::<Box<F>, F, $($A,)* R>,
+                data,
+                drop,
+            }
+        }
+
+        /// Convert a borrowed `FnMut` closure into a C callback. This never allocates, and `drop`
+        /// does nothing.
+        ///
+        /// The trampoline must not be called re-entrantly, nor after the borrow of the closure has
+        /// expired.
+        pub fn $fn_mut_ref<'a, F, $($A,)* R>(f: &'a mut F)
+            -> RawCallback<unsafe extern "C" fn(*mut c_void $(, $A)*) -> R>
+            where F: FnMut($($A),*) -> R,
+                  R: Default
+        {
+            let (data, drop) = MaybeBox::new(f).into_raw_with_drop();
+            RawCallback {
+                func: $fn_mut_trampoline::<&'a mut F, F, $($A,)* R>,
+                data,
+                drop,
+            }
+        }
+
+        unsafe extern "C" fn $fn_mut_trampoline<P, F, $($A,)* R>(mut data: *mut c_void $(, $arg: $A)*) -> R
+            where P: DerefMut<Target = F>,
+                  F: FnMut($($A),*) -> R,
+                  R: Default
+        {
+            // The closure lives behind the pointer, so mutating it through a copy of the word is
+            // fine.
+            let mut f = MaybeBox::<P>::mut_from_raw(&mut data);
+            catch(move || (**f)($($arg),*))
+        }
+
+        /// Convert an `Fn` closure into a C callback. This allocates unless the closure is
+        /// zero-sized.
+        ///
+        /// The trampoline must not be called after `drop` has been called or any borrows captured
+        /// by the closure have expired.
+        pub fn $fn_<F, $($A,)* R>(f: F)
+            -> RawCallback<unsafe extern "C" fn(*mut c_void $(, $A)*) -> R>
+            where F: Fn($($A),*) -> R,
+                  R: Default
+        {
+            let (data, drop) = MaybeBox::new(Box::new(f)).into_raw_with_drop();
+            RawCallback {
+                func: $fn_trampoline::<Box<F>, F, $($A,)* R>,
+                data,
+                drop,
+            }
+        }
+
+        /// Convert a borrowed `Fn` closure into a C callback. This never allocates, and `drop`
+        /// does nothing.
+        ///
+        /// The trampoline must not be called after the borrow of the closure has expired.
+        pub fn $fn_ref<'a, F, $($A,)* R>(f: &'a F)
+            -> RawCallback<unsafe extern "C" fn(*mut c_void $(, $A)*) -> R>
+            where F: Fn($($A),*) -> R,
+                  R: Default
+        {
+            let (data, drop) = MaybeBox::new(f).into_raw_with_drop();
+            RawCallback {
+                func: $fn_trampoline::<&'a F, F, $($A,)* R>,
+                data,
+                drop,
+            }
+        }
+
+        unsafe extern "C" fn $fn_trampoline<P, F, $($A,)* R>(data: *mut c_void $(, $arg: $A)*) -> R
+            where P: Deref<Target = F>,
+                  F: Fn($($A),*) -> R,
+                  R: Default
+        {
+            let f = MaybeBox::<P>::ref_from_raw(data);
+            catch(move || (**f)($($arg),*))
+        }
+
+        /// Convert an `FnOnce` closure into a C callback. This allocates unless the closure is
+        /// zero-sized.
+        ///
+        /// Calling the trampoline consumes the closure, so exactly one of `func` or `drop` must be
+        /// called, exactly once. The trampoline must not be called after any borrows captured by
+        /// the closure have expired.
+        pub fn $fn_once<F, $($A,)* R>(f: F)
+            -> RawCallback<unsafe extern "C" fn(*mut c_void $(, $A)*) -> R>
+            where F: FnOnce($($A),*) -> R,
+                  R: Default
+        {
+            let (data, drop) = MaybeBox::new(Box::new(f)).into_raw_with_drop();
+            RawCallback {
+                func: $fn_once_trampoline::<F, $($A,)* R>,
+                data,
+                drop,
+            }
+        }
+
+        unsafe extern "C" fn $fn_once_trampoline<F, $($A,)* R>(data: *mut c_void $(, $arg: $A)*) -> R
+            where F: FnOnce($($A),*) -> R,
+                  R: Default
+        {
+            let f = MaybeBox::<Box<F>>::from_raw(data);
+            catch(move || (f.into_inner())($($arg),*))
+        }
+    )*};
+}
+
+callbacks! {
+    fn_mut_0, fn_mut_ref_0, fn_mut_trampoline_0, fn_0, fn_ref_0, fn_trampoline_0,
+    fn_once_0, fn_once_trampoline_0, ();
+    fn_mut_1, fn_mut_ref_1, fn_mut_trampoline_1, fn_1, fn_ref_1, fn_trampoline_1,
+    fn_once_1, fn_once_trampoline_1, (a: A);
+    fn_mut_2, fn_mut_ref_2, fn_mut_trampoline_2, fn_2, fn_ref_2, fn_trampoline_2,
+    fn_once_2, fn_once_trampoline_2, (a: A, b: B);
+    fn_mut_3, fn_mut_ref_3, fn_mut_trampoline_3, fn_3, fn_ref_3, fn_trampoline_3,
+    fn_once_3, fn_once_trampoline_3, (a: A, b: B, c: C);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn fn_mut() {
+        let mut total = 0;
+        {
+            let cb = fn_mut_1(|x: u32| {
+                total += x;
+                total
+            });
+            unsafe {
+                assert_eq!((cb.func)(cb.data, 1), 1);
+                assert_eq!((cb.func)(cb.data, 2), 3);
+                (cb.drop)(cb.data);
+            }
+        }
+        assert_eq!(total, 3);
+
+        let mut count = 0u32;
+        let cb = fn_mut_0(move || {
+            count += 1;
+            count
+        });
+        unsafe {
+            assert_eq!((cb.func)(cb.data), 1);
+            assert_eq!((cb.func)(cb.data), 2);
+            (cb.drop)(cb.data);
+        }
+
+        // A closure's own state persists between calls whatever its size, since it's boxed.
+        let mut count = 0usize;
+        let cb = fn_mut_0(move || {
+            count += 1;
+            count
+        });
+        unsafe {
+            assert_eq!((cb.func)(cb.data), 1);
+            assert_eq!((cb.func)(cb.data), 2);
+            (cb.drop)(cb.data);
+        }
+    }
+
+    #[test]
+    fn fn_() {
+        let cb = fn_2(|a: u32, b: u32| a * b);
+        unsafe {
+            assert_eq!((cb.func)(cb.data, 3, 4), 12);
+            (cb.drop)(cb.data);
+        }
+
+        // A closure that captures nothing doesn't allocate, so the word is a dangling pointer.
+        let cb = fn_1(|a: u64| a + 5);
+        assert_eq!(cb.data as usize, 1);
+        unsafe {
+            assert_eq!((cb.func)(cb.data, 1), 6);
+            (cb.drop)(cb.data);
+        }
+
+        // Captures with padding bytes are boxed along with the rest of the closure.
+        let k = (1u8, 2u16);
+        let cb = fn_1(move |a: u16| a + k.0 as u16 + k.1);
+        unsafe {
+            assert_eq!((cb.func)(cb.data, 1), 4);
+            assert_eq!((cb.func)(cb.data, 2), 5);
+            (cb.drop)(cb.data);
+        }
+        let cb = fn_once_0(move || k.1);
+        unsafe {
+            assert_eq!((cb.func)(cb.data), 2);
+        }
+
+        // So does a `Cell` captured by an `Fn` closure.
+        let calls = Cell::new(0usize);
+        let cb = fn_0(move || {
+            calls.set(calls.get() + 1);
+            calls.get()
+        });
+        unsafe {
+            assert_eq!((cb.func)(cb.data), 1);
+            assert_eq!((cb.func)(cb.data), 2);
+            (cb.drop)(cb.data);
+        }
+    }
+
+    #[test]
+    fn borrowed() {
+        // A borrowed closure is stored inline as a reference, so the word is its address and
+        // nothing is allocated.
+        let mut total = 0;
+        let mut add = |x: u32| {
+            total += x;
+            total
+        };
+        let addr = &mut add as *mut _ as *mut c_void;
+        let cb = fn_mut_ref_1(&mut add);
+        assert_eq!(cb.data, addr);
+        unsafe {
+            assert_eq!((cb.func)(cb.data, 1), 1);
+            assert_eq!((cb.func)(cb.data, 2), 3);
+            (cb.drop)(cb.data);
+        }
+        assert_eq!(add(4), 7);
+
+        // Captures with padding bytes are fine too, since only the reference is in the word.
+        let k = (1u8, 2u16);
+        let mul = move |a: u16, b: u16| a * b + k.0 as u16 + k.1;
+        let cb = fn_ref_2(&mul);
+        assert_eq!(cb.data as *const c_void, &mul as *const _ as *const c_void);
+        unsafe {
+            assert_eq!((cb.func)(cb.data, 3, 4), 15);
+            (cb.drop)(cb.data);
+        }
+    }
+
+    #[test]
+    fn fn_once() {
+        let rc = Rc::new(());
+        let rc_clone = rc.clone();
+        let cb = fn_once_3(move |a: u8, b: u16, c: u32| {
+            drop(rc_clone);
+            a as u32 + b as u32 + c
+        });
+        unsafe {
+            assert_eq!((cb.func)(cb.data, 1, 2, 3), 6);
+        }
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let rc_clone = rc.clone();
+        let cb = fn_once_0(move || drop(rc_clone));
+        assert_eq!(Rc::strong_count(&rc), 2);
+        unsafe {
+            (cb.drop)(cb.data);
+        }
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn panics() {
+        let cb = fn_1(|x: u32| {
+            if x == 0 {
+                panic!("zero!");
+            }
+            x
+        });
+        assert!(take_panic().is_none());
+        unsafe {
+            assert_eq!((cb.func)(cb.data, 0), 0);
+            assert_eq!((cb.func)(cb.data, 7), 7);
+            (cb.drop)(cb.data);
+        }
+        let payload = take_panic().unwrap();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"zero!"));
+        assert!(take_panic().is_none());
+
+        let cb = fn_once_0(|| -> () { panic!("once") });
+        unsafe {
+            (cb.func)(cb.data);
+        }
+        let res = panic::catch_unwind(resume_panic);
+        assert_eq!(res.unwrap_err().downcast_ref::<&str>(), Some(&"once"));
+        resume_panic();
+    }
+}
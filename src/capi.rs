@@ -0,0 +1,79 @@
+//! `extern "C"` functions for working with `ErasedMaybeBox`es from C. These are declared in
+//! `include/maybe_box.h`, where an `ErasedMaybeBox` appears as a `maybe_box_erased`.
+//!
+//! An `ErasedMaybeBox` created in Rust can be handed to C, which can then clone it, drop it and
+//! query its type without knowing what it holds, before passing it back to Rust to be downcast.
+
+use std::os::raw::c_char;
+use std::ptr;
+
+use ErasedMaybeBox;
+
+/// Clone `*mb` into `*out`. Returns `false`, leaving `*out` untouched, if `*mb` isn't cloneable.
+///
+/// # Safety
+///
+/// `mb` must point to a live `ErasedMaybeBox` and `out` must be valid for writes. Any
+/// `ErasedMaybeBox` previously in `*out` is overwritten without being dropped.
+#[no_mangle]
+pub unsafe extern "C" fn maybe_box_erased_clone(
+    mb: *const ErasedMaybeBox,
+    out: *mut ErasedMaybeBox,
+) -> bool {
+    match (*mb).try_clone() {
+        Some(clone) => {
+            ptr::write(out, clone);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Drop the `ErasedMaybeBox` at `*mb`. `*mb` must not be used afterwards.
+///
+/// # Safety
+///
+/// `mb` must point to a live `ErasedMaybeBox`.
+#[no_mangle]
+pub unsafe extern "C" fn maybe_box_erased_drop(mb: *mut ErasedMaybeBox) {
+    ptr::drop_in_place(mb);
+}
+
+/// Whether `*mb` can be cloned with `maybe_box_erased_clone`.
+///
+/// # Safety
+///
+/// `mb` must point to a live `ErasedMaybeBox`.
+#[no_mangle]
+pub unsafe extern "C" fn maybe_box_erased_is_cloneable(mb: *const ErasedMaybeBox) -> bool {
+    (*mb).is_cloneable()
+}
+
+/// Whether `*a` and `*b` hold values of the same type.
+///
+/// # Safety
+///
+/// `a` and `b` must point to live `ErasedMaybeBox`es.
+#[no_mangle]
+pub unsafe extern "C" fn maybe_box_erased_same_type(
+    a: *const ErasedMaybeBox,
+    b: *const ErasedMaybeBox,
+) -> bool {
+    (*a).type_id() == (*b).type_id()
+}
+
+/// Get the name of the type held in `*mb`. The name is UTF-8 and is *not* nul-terminated; its
+/// length in bytes is written to `*len`. The returned pointer remains valid forever.
+///
+/// # Safety
+///
+/// `mb` must point to a live `ErasedMaybeBox` and `len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn maybe_box_erased_type_name(
+    mb: *const ErasedMaybeBox,
+    len: *mut usize,
+) -> *const c_char {
+    let name = (*mb).type_name();
+    ptr::write(len, name.len());
+    name.as_ptr() as *const c_char
+}
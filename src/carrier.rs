@@ -0,0 +1,141 @@
+use std::mem::{self, MaybeUninit};
+use std::os::raw::c_void;
+use std::ptr::NonNull;
+
+use MaybeBoxIn;
+
+/// A type that a `MaybeBoxIn` can be stored in. The size and alignment of the carrier determine
+/// which values can be stored inline. Values that don't fit are boxed, in which case the pointer to
+/// the box is stored at the start of the carrier.
+///
+/// This is implemented for `usize`, `isize`, `u64`, `i64`, `u32`, `i32`, `*mut c_void`,
+/// `NonNull<()>` and `[usize; N]`. It can also be implemented for other plain-data types, such as
+/// a `#[repr(C)]` union mirroring a field of a C struct:
+///
+/// ```
+/// use std::mem::MaybeUninit;
+/// use std::os::raw::{c_long, c_void};
+/// use maybe_box::{Carrier, MaybeBoxIn};
+///
+/// #[repr(C)]
+/// #[derive(Clone, Copy)]
+/// union Slot {
+///     ptr: *mut c_void,
+///     long: c_long,
+/// }
+///
+/// unsafe impl Carrier for Slot {
+///     const CARRIES_PROVENANCE: bool = true;
+///     type Storage = MaybeUninit<Slot>;
+/// }
+///
+/// let slot: Slot = MaybeBoxIn::<u16, Slot>::new(123).into_carrier();
+/// let mb = unsafe { MaybeBoxIn::<u16, Slot>::from_carrier(slot) };
+/// assert_eq!(*mb, 123);
+/// ```
+///
+/// A `NonNull<()>` carrier is never zero, so `Option<MaybeBoxIn<T, NonNull<()>>>` is the same size
+/// as a pointer. Since any inline value might be all zeroes, it boxes every value that isn't
+/// zero-sized. To store values that are never zero inline, use `NonNullMaybeBox`.
+///
+/// # Pointers in inline values
+///
+/// An inline value can hold pointers, such as a `&T` or a `Box<T>`. If `CARRIES_PROVENANCE` is
+/// `false`, reading the value out as part of the carrier would lose their provenance, and the
+/// value returned by `from_carrier` couldn't be dereferenced. So `MaybeBoxIn::into_carrier`
+/// exposes the provenance of the pointers in an inline value whose type might hold them, according
+/// to `NoUninit::HOLDS_POINTERS`, and `from_carrier` recovers it. The value stays inline and its
+/// bytes are unchanged.
+///
+/// # Safety
+///
+/// Copying a value of the type must copy all of its bytes. `MaybeBoxIn::into_carrier` copies the
+/// bytes of an inline value out as a value of the type, along with the zeroed bytes around it, so
+/// any bit pattern must be a valid value of the type, except for the all-zero bit pattern if
+/// `NON_NULL` is `true`. Those bytes are always initialized, since `into_carrier` requires the
+/// inline value to be `NoUninit`. If `NON_NULL` is `true`, the type must have room for a pointer.
+///
+/// `CARRIES_PROVENANCE` may only be `true` if copying a value of the type keeps the provenance of
+/// a pointer stored in it, as copying a raw pointer or a union with a pointer field does. Integer
+/// types don't, so they must set it to `false`.
+///
+/// `Storage` must be `MaybeUninit<Self>`, or `Self` if `NON_NULL` is `true`.
+pub unsafe trait Carrier: Copy {
+    /// Whether values of this type keep the provenance of a pointer stored in them, ie. whether it's
+    /// a pointer type or a union with a pointer field. If not, `MaybeBoxIn::into_carrier` exposes
+    /// the provenance of a boxed value's pointer, and of any pointers in an inline value, so that
+    /// `from_carrier` can recover it.
+    const CARRIES_PROVENANCE: bool;
+
+    /// Whether the all-zero bit pattern is an invalid value of this type, as for `NonNull<()>`.
+    /// Since any non-zero-sized value might be all zeroes, a `MaybeBoxIn` with such a carrier
+    /// boxes everything except zero-sized values.
+    const NON_NULL: bool = false;
+
+    /// What a `MaybeBoxIn<T, Self>` holds the carrier as. This is `MaybeUninit<Self>`, since an
+    /// inline value might not be a valid `Self` until it's handed out with `into_carrier`, except
+    /// for carriers with `NON_NULL` set, which always hold a pointer and use `Self` so that
+    /// `Option<MaybeBoxIn<T, Self>>` can use its niche.
+    type Storage: Copy;
+
+    /// The name a `MaybeBoxIn<T, Self>` is given when debug-formatted, which is the name of the
+    /// alias for it, if there is one.
+    #[doc(hidden)]
+    const DEBUG_NAME: &'static str = "MaybeBoxIn";
+}
+
+unsafe impl Carrier for usize {
+    const CARRIES_PROVENANCE: bool = false;
+    type Storage = MaybeUninit<Self>;
+    const DEBUG_NAME: &'static str = "MaybeBox";
+}
+
+unsafe impl Carrier for isize {
+    const CARRIES_PROVENANCE: bool = false;
+    type Storage = MaybeUninit<Self>;
+}
+
+unsafe impl Carrier for u64 {
+    const CARRIES_PROVENANCE: bool = false;
+    type Storage = MaybeUninit<Self>;
+    const DEBUG_NAME: &'static str = "MaybeBox64";
+}
+
+unsafe impl Carrier for i64 {
+    const CARRIES_PROVENANCE: bool = false;
+    type Storage = MaybeUninit<Self>;
+}
+
+unsafe impl Carrier for u32 {
+    const CARRIES_PROVENANCE: bool = false;
+    type Storage = MaybeUninit<Self>;
+    const DEBUG_NAME: &'static str = "MaybeBox32";
+}
+
+unsafe impl Carrier for i32 {
+    const CARRIES_PROVENANCE: bool = false;
+    type Storage = MaybeUninit<Self>;
+}
+
+unsafe impl Carrier for *mut c_void {
+    const CARRIES_PROVENANCE: bool = true;
+    type Storage = MaybeUninit<Self>;
+}
+
+unsafe impl Carrier for NonNull<()> {
+    const CARRIES_PROVENANCE: bool = true;
+    const NON_NULL: bool = true;
+    type Storage = NonNull<()>;
+}
+
+unsafe impl<const N: usize> Carrier for [usize; N] {
+    const CARRIES_PROVENANCE: bool = false;
+    type Storage = MaybeUninit<Self>;
+    const DEBUG_NAME: &'static str = "MaybeBoxN";
+}
+
+const _: () = {
+    assert!(mem::size_of::<Option<MaybeBoxIn<u8, NonNull<()>>>>() == mem::size_of::<usize>());
+    assert!(mem::size_of::<Option<MaybeBoxIn<(), NonNull<()>>>>() == mem::size_of::<usize>());
+    assert!(mem::size_of::<Option<MaybeBoxIn<String, NonNull<()>>>>() == mem::size_of::<usize>());
+};
@@ -0,0 +1,45 @@
+//! The allocator a `MaybeBoxIn` frees its boxed value with.
+
+use std::alloc::Layout;
+#[cfg(not(feature = "allocator"))]
+use std::alloc;
+#[cfg(feature = "allocator")]
+use std::ptr::NonNull;
+
+#[cfg(feature = "allocator")]
+use allocator_api2::alloc::Allocator;
+
+/// An allocator that a `MaybeBoxIn` can free its boxed value with. This is implemented for
+/// `Global`, and with the `allocator` feature for every `allocator_api2` `Allocator`.
+///
+/// # Safety
+///
+/// `dealloc` must free memory allocated by the allocator. `Global`'s must free memory allocated
+/// by `Box`, since that's what `MaybeBox::new` boxes values with.
+pub unsafe trait BoxAlloc {
+    /// Free the allocation at `ptr`, which was allocated by this allocator with `layout`.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+}
+
+/// The global allocator, which values are boxed with unless another allocator is given. With the
+/// `allocator` feature this is `allocator_api2`'s `Global` instead.
+#[cfg(not(feature = "allocator"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Global;
+
+#[cfg(not(feature = "allocator"))]
+unsafe impl BoxAlloc for Global {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc::dealloc(ptr, layout)
+    }
+}
+
+#[cfg(feature = "allocator")]
+pub use allocator_api2::alloc::Global;
+
+#[cfg(feature = "allocator")]
+unsafe impl<A: Allocator> BoxAlloc for A {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
@@ -0,0 +1,218 @@
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::ptr::{self, NonNull};
+
+use {Carrier, MaybeBoxIn};
+
+/// Stored at the start of the heap allocation of a `MaybeBoxDyn<Dyn>`, followed by the value.
+#[repr(C)]
+struct Header<Dyn: ?Sized> {
+    /// Turns a pointer to the value into a (fat) pointer to a `Dyn`.
+    coerce: unsafe fn(*mut ()) -> *mut Dyn,
+    /// The offset of the value from the start of the header.
+    offset: usize,
+}
+
+#[repr(C)]
+struct Inner<Dyn: ?Sized, T> {
+    header: Header<Dyn>,
+    value: T,
+}
+
+/// Hold a value of an unsized type, such as `dyn Trait`, in the space for a `usize`.
+///
+/// A `Box<dyn Trait>` takes two words, so a `MaybeBox<Box<dyn Trait>>` has to box it again. A
+/// `MaybeBoxDyn<dyn Trait>` instead keeps what it needs to know about the value's type in a header
+/// at the start of the value's heap allocation, so it only needs one word and one allocation.
+/// Since there's no room in the word for that information, values are always boxed, even if
+/// they're zero-sized.
+///
+/// Use the `maybe_box_dyn!` macro to convert a `MaybeBox<T>` into a `MaybeBoxDyn<Dyn>` where
+/// `T: Dyn`.
+///
+/// This type is guaranteed to be the same size as a `usize`.
+#[repr(transparent)]
+pub struct MaybeBoxDyn<Dyn: ?Sized> {
+    header: NonNull<Header<Dyn>>,
+    _ph: PhantomData<Box<Dyn>>,
+}
+
+unsafe impl<Dyn: ?Sized + Send> Send for MaybeBoxDyn<Dyn> {}
+unsafe impl<Dyn: ?Sized + Sync> Sync for MaybeBoxDyn<Dyn> {}
+
+/// Convert a `MaybeBoxIn<T, C>` into a `MaybeBoxDyn<Dyn>`, where `T` can be unsized to `Dyn`. This
+/// is the equivalent of coercing a `Box<T>` into a `Box<dyn Trait>`.
+///
+/// ```
+/// # #[macro_use] extern crate maybe_box;
+/// # use maybe_box::{MaybeBox, MaybeBoxDyn};
+/// # use std::fmt::Display;
+/// # fn main() {
+/// let mb: MaybeBoxDyn<dyn Display> = maybe_box_dyn!(MaybeBox::new(123));
+/// assert_eq!(mb.to_string(), "123");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! maybe_box_dyn {
+    ($mb:expr) => {{
+        let mb = $mb;
+        // The closure is just an unsizing coercion, as required by `into_dyn`.
+        unsafe { $crate::MaybeBoxIn::into_dyn(mb, |ptr| ptr) }
+    }};
+}
+
+impl<T, C: Carrier> MaybeBoxIn<T, C> {
+    /// Convert a `MaybeBoxIn<T, C>` into a `MaybeBoxDyn<Dyn>`. This always allocates. Prefer the
+    /// `maybe_box_dyn!` macro, which is safe.
+    ///
+    /// # Safety
+    ///
+    /// `coerce` must return its argument unchanged, other than unsizing it, ie. it must be
+    /// `|ptr| ptr`.
+    pub unsafe fn into_dyn<Dyn: ?Sized>(self, coerce: fn(*mut T) -> *mut Dyn) -> MaybeBoxDyn<Dyn> {
+        MaybeBoxDyn::new_unsize(self.into_inner(), coerce)
+    }
+}
+
+impl<Dyn: ?Sized> MaybeBoxDyn<Dyn> {
+    /// Box a `T` into a `MaybeBoxDyn<Dyn>`, using `coerce` to unsize a pointer to the `T`.
+    ///
+    /// # Safety
+    ///
+    /// `coerce` must return its argument unchanged, other than unsizing it, ie. it must be
+    /// `|ptr| ptr`.
+    pub unsafe fn new_unsize<T>(t: T, coerce: fn(*mut T) -> *mut Dyn) -> MaybeBoxDyn<Dyn> {
+        // Function pointers are ABI-compatible if their argument types are thin pointers.
+        let coerce: unsafe fn(*mut ()) -> *mut Dyn = mem::transmute(coerce);
+        let inner = Box::new(Inner {
+            header: Header {
+                coerce,
+                offset: mem::offset_of!(Inner<Dyn, T>, value),
+            },
+            value: t,
+        });
+        MaybeBoxDyn {
+            header: NonNull::new_unchecked(Box::into_raw(inner)).cast(),
+            _ph: PhantomData,
+        }
+    }
+
+    /// Get a pointer to the value.
+    #[inline]
+    fn value_ptr(&self) -> *mut Dyn {
+        unsafe {
+            let header = self.header.as_ptr();
+            let value = (header as *mut u8).add((*header).offset);
+            ((*header).coerce)(value as *mut ())
+        }
+    }
+
+    /// Consume the `MaybeBoxDyn<Dyn>` and return the pointer to its heap allocation, for passing to
+    /// C code as a `void *`.
+    ///
+    /// To avoid a leak the pointer must eventually be turned back into a `MaybeBoxDyn<Dyn>` with
+    /// `from_raw`.
+    pub fn into_raw(self) -> *mut c_void {
+        ManuallyDrop::new(self).header.as_ptr() as *mut c_void
+    }
+
+    /// Reconstruct a `MaybeBoxDyn<Dyn>` from a pointer returned by `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBoxDyn::<Dyn>::into_raw` for the same `Dyn`, and
+    /// ownership of the value is transferred back to the returned `MaybeBoxDyn<Dyn>`. This means
+    /// that `from_raw` can be called at most once for each call to `into_raw`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> MaybeBoxDyn<Dyn> {
+        MaybeBoxDyn {
+            header: NonNull::new_unchecked(raw as *mut Header<Dyn>),
+            _ph: PhantomData,
+        }
+    }
+}
+
+impl<Dyn: ?Sized> Drop for MaybeBoxDyn<Dyn> {
+    fn drop(&mut self) {
+        let value = self.value_ptr();
+        unsafe {
+            // This is the layout of the `Inner<Dyn, T>` that was allocated, since it's `repr(C)`.
+            let value_layout = Layout::for_value(&*value);
+            let (layout, _) = match Layout::new::<Header<Dyn>>().extend(value_layout) {
+                Ok(layout) => layout,
+                Err(_) => unreachable!(),
+            };
+            ptr::drop_in_place(value);
+            alloc::dealloc(self.header.as_ptr() as *mut u8, layout.pad_to_align());
+        }
+    }
+}
+
+impl<Dyn: ?Sized> Deref for MaybeBoxDyn<Dyn> {
+    type Target = Dyn;
+
+    fn deref(&self) -> &Dyn {
+        unsafe { &*self.value_ptr() }
+    }
+}
+
+impl<Dyn: ?Sized> DerefMut for MaybeBoxDyn<Dyn> {
+    fn deref_mut(&mut self) -> &mut Dyn {
+        unsafe { &mut *self.value_ptr() }
+    }
+}
+
+impl<Dyn: ?Sized + fmt::Debug> fmt::Debug for MaybeBoxDyn<Dyn> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner: &Dyn = self;
+        f.debug_tuple("MaybeBoxDyn").field(&inner).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::any::Any;
+    use std::rc::Rc;
+    use MaybeBox;
+
+    #[repr(align(64))]
+    #[derive(Debug)]
+    struct OverAligned;
+
+    #[test]
+    fn trait_objects() {
+        let mb: MaybeBoxDyn<dyn fmt::Debug> = maybe_box_dyn!(MaybeBox::new(OverAligned));
+        assert_eq!(format!("{:?}", mb), "MaybeBoxDyn(OverAligned)");
+        assert_eq!(&*mb as *const dyn fmt::Debug as *const () as usize % 64, 0);
+
+        let mut total = 0;
+        {
+            let mut mb: MaybeBoxDyn<dyn FnMut(u32)> = maybe_box_dyn!(MaybeBox::new(|x| total += x));
+            mb(1);
+            mb(2);
+        }
+        assert_eq!(total, 3);
+
+        let mb: MaybeBoxDyn<dyn Any> = maybe_box_dyn!(MaybeBox::new(()));
+        assert!(mb.is::<()>());
+        let raw = mb.into_raw();
+        let mut mb = unsafe { MaybeBoxDyn::<dyn Any>::from_raw(raw) };
+        assert!(mb.downcast_mut::<u8>().is_none());
+
+        let mb: MaybeBoxDyn<[u64]> = maybe_box_dyn!(MaybeBox::new([1u64, 2, 3]));
+        assert_eq!(&*mb, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn drops() {
+        let rc = Rc::new(());
+        let mb: MaybeBoxDyn<dyn Any> = maybe_box_dyn!(MaybeBox::new((rc.clone(), [0u8; 100])));
+        assert_eq!(Rc::strong_count(&rc), 2);
+        drop(mb);
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+}
@@ -0,0 +1,353 @@
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::os::raw::c_void;
+use std::ptr;
+
+use tagged::fits_tagged;
+use word_enum::RawWord;
+use NoUninit;
+
+/// A value of one of two types. Produced by `MaybeEither::into_either`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Either<A, B> {
+    /// An `A`.
+    Left(A),
+    /// A `B`.
+    Right(B),
+}
+
+/// Hold either an `A` or a `B` in the space for a `usize`, only boxing the value if necessary.
+///
+/// The lowest bit of the word records which type is held. Each type is stored inline, in the
+/// bytes of the word that don't hold that bit, if it fits, and is boxed otherwise. Boxes are
+/// allocated with an alignment of at least 2 so that the bit is free.
+///
+/// This differs from `MaybeBox` for types that fill the whole word. The byte holding the tag bit
+/// isn't available to an inline value, so a type is stored inline only if it fits in the other
+/// bytes of the word at its alignment, the same as in a `TaggedMaybeBox<T, 1>`. For example, a
+/// `u32` or a `[u8; 7]` is stored inline on targets with 64-bit words, but a `u64`, a `usize` or a
+/// `&T` is boxed, even though `MaybeBox` stores them inline. A `u64` has no bit to spare, and
+/// although a `&T` usually has a free low bit, nothing about an arbitrary `A` or `B` says whether
+/// it does. `LEFT_INLINE` and `RIGHT_INLINE` say which rule applies to a given `A` and `B`.
+///
+/// This type is guaranteed to be the same size as a `usize`.
+#[repr(transparent)]
+pub struct MaybeEither<A, B> {
+    raw: RawWord<Either<A, B>, 1>,
+}
+
+impl<A, B> MaybeEither<A, B> {
+    /// Whether an `A` is stored inline. This is `false` for types such as `u64` and `&T` that
+    /// `MaybeBox` stores inline but which leave no room for the tag bit.
+    pub const LEFT_INLINE: bool = fits_tagged::<A>(1);
+
+    /// Whether a `B` is stored inline. See `LEFT_INLINE`.
+    pub const RIGHT_INLINE: bool = fits_tagged::<B>(1);
+
+    /// Wrap an `A` into a `MaybeEither<A, B>`. This will allocate if the `A` doesn't fit in the
+    /// bytes of a `usize` not needed for the tag bit.
+    #[inline]
+    pub fn new_left(a: A) -> MaybeEither<A, B> {
+        MaybeEither {
+            raw: unsafe { RawWord::new(a, 0) },
+        }
+    }
+
+    /// Wrap a `B` into a `MaybeEither<A, B>`. This will allocate if the `B` doesn't fit in the
+    /// bytes of a `usize` not needed for the tag bit.
+    #[inline]
+    pub fn new_right(b: B) -> MaybeEither<A, B> {
+        MaybeEither {
+            raw: unsafe { RawWord::new(b, 1) },
+        }
+    }
+
+    /// Wrap an `Either<A, B>` into a `MaybeEither<A, B>`.
+    pub fn new(either: Either<A, B>) -> MaybeEither<A, B> {
+        match either {
+            Either::Left(a) => MaybeEither::new_left(a),
+            Either::Right(b) => MaybeEither::new_right(b),
+        }
+    }
+
+    /// Whether this holds a `B`.
+    pub fn is_right(&self) -> bool {
+        self.raw.tag() == 1
+    }
+
+    /// Whether this holds an `A`.
+    pub fn is_left(&self) -> bool {
+        !self.is_right()
+    }
+
+    /// Consume the `MaybeEither<A, B>` and return the value it holds.
+    pub fn into_either(self) -> Either<A, B> {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            let raw = ptr::read(&this.raw);
+            if this.is_right() {
+                Either::Right(raw.take())
+            } else {
+                Either::Left(raw.take())
+            }
+        }
+    }
+
+    /// Borrow the value this holds.
+    pub fn as_ref(&self) -> Either<&A, &B> {
+        unsafe {
+            if self.is_right() {
+                Either::Right(self.raw.get())
+            } else {
+                Either::Left(self.raw.get())
+            }
+        }
+    }
+
+    /// Mutably borrow the value this holds.
+    pub fn as_mut(&mut self) -> Either<&mut A, &mut B> {
+        unsafe {
+            if self.is_right() {
+                Either::Right(self.raw.get_mut())
+            } else {
+                Either::Left(self.raw.get_mut())
+            }
+        }
+    }
+
+    /// Apply `f` to the value if it's an `A`.
+    pub fn map_left<C, F: FnOnce(A) -> C>(self, f: F) -> MaybeEither<C, B> {
+        match self.into_either() {
+            Either::Left(a) => MaybeEither::new_left(f(a)),
+            Either::Right(b) => MaybeEither::new_right(b),
+        }
+    }
+
+    /// Apply `f` to the value if it's a `B`.
+    pub fn map_right<C, F: FnOnce(B) -> C>(self, f: F) -> MaybeEither<A, C> {
+        match self.into_either() {
+            Either::Left(a) => MaybeEither::new_left(a),
+            Either::Right(b) => MaybeEither::new_right(f(b)),
+        }
+    }
+}
+
+impl<A: NoUninit, B: NoUninit> MaybeEither<A, B> {
+    /// Consume the `MaybeEither<A, B>` and return the word it's stored in, for passing to C code
+    /// as a `void *`.
+    ///
+    /// If `A` or `B` is always boxed, it doesn't need to implement `NoUninit`; use
+    /// `into_raw_boxed_left`, `into_raw_boxed_right` or `into_boxed_raw` instead.
+    ///
+    /// The word must eventually be turned back into a `MaybeEither<A, B>` with `from_raw`. See the
+    /// crate docs on raw words.
+    pub fn into_raw(self) -> *mut c_void {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            let raw = ptr::read(&this.raw);
+            if this.is_right() {
+                raw.into_raw::<B>()
+            } else {
+                raw.into_raw::<A>()
+            }
+        }
+    }
+}
+
+impl<A, B: NoUninit> MaybeEither<A, B> {
+    /// The same as `into_raw`, for an `A` that's boxed, so that it doesn't need to implement
+    /// `NoUninit`. Fails to compile if an `A` would be stored inline.
+    pub fn into_raw_boxed_left(self) -> *mut c_void {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            let raw = ptr::read(&this.raw);
+            if this.is_right() {
+                raw.into_raw::<B>()
+            } else {
+                raw.into_boxed_raw::<A>()
+            }
+        }
+    }
+}
+
+impl<A: NoUninit, B> MaybeEither<A, B> {
+    /// The same as `into_raw`, for a `B` that's boxed, so that it doesn't need to implement
+    /// `NoUninit`. Fails to compile if a `B` would be stored inline.
+    ///
+    /// This is the one to use for a small handle or a boxed struct:
+    ///
+    /// ```
+    /// # use maybe_box::{Either, MaybeEither};
+    /// struct Connection {
+    ///     addr: String,
+    ///     port: u16,
+    /// }
+    ///
+    /// let conn = Connection { addr: String::from("localhost"), port: 80 };
+    /// let raw = MaybeEither::<u32, Connection>::new_right(conn).into_raw_boxed_right();
+    /// let either = unsafe { MaybeEither::<u32, Connection>::from_raw(raw) };
+    /// match either.into_either() {
+    ///     Either::Right(conn) => assert_eq!((&conn.addr[..], conn.port), ("localhost", 80)),
+    ///     Either::Left(_) => unreachable!(),
+    /// }
+    /// ```
+    pub fn into_raw_boxed_right(self) -> *mut c_void {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            let raw = ptr::read(&this.raw);
+            if this.is_right() {
+                raw.into_boxed_raw::<B>()
+            } else {
+                raw.into_raw::<A>()
+            }
+        }
+    }
+}
+
+impl<A, B> MaybeEither<A, B> {
+    /// The same as `into_raw`, for an `A` and a `B` that are both boxed, so that neither needs to
+    /// implement `NoUninit`. Fails to compile if either would be stored inline.
+    pub fn into_boxed_raw(self) -> *mut c_void {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            let raw = ptr::read(&this.raw);
+            if this.is_right() {
+                raw.into_boxed_raw::<B>()
+            } else {
+                raw.into_boxed_raw::<A>()
+            }
+        }
+    }
+
+    /// Reconstruct a `MaybeEither<A, B>` from a word returned by `into_raw`, `into_boxed_raw`,
+    /// `into_raw_boxed_left` or `into_raw_boxed_right`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by one of those methods of `MaybeEither<A, B>` for the same
+    /// `A` and `B`, and not already turned back into a `MaybeEither<A, B>`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> MaybeEither<A, B> {
+        MaybeEither {
+            raw: RawWord::from_raw(raw),
+        }
+    }
+}
+
+impl<A, B> Drop for MaybeEither<A, B> {
+    fn drop(&mut self) {
+        unsafe {
+            if self.is_right() {
+                self.raw.drop_in_place::<B>();
+            } else {
+                self.raw.drop_in_place::<A>();
+            }
+        }
+    }
+}
+
+impl<A, B> From<Either<A, B>> for MaybeEither<A, B> {
+    fn from(either: Either<A, B>) -> MaybeEither<A, B> {
+        MaybeEither::new(either)
+    }
+}
+
+impl<A: fmt::Debug, B: fmt::Debug> fmt::Debug for MaybeEither<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("MaybeEither").field(&self.as_ref()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn handle_or_struct() {
+        let mut e = MaybeEither::<u32, [u8; 32]>::new_left(7);
+        assert!(e.is_left());
+        assert_eq!(e.as_ref(), Either::Left(&7));
+        if let Either::Left(n) = e.as_mut() {
+            *n += 1;
+        }
+        assert_eq!(format!("{:?}", e), "MaybeEither(Left(8))");
+        let e = e.map_left(|n| n as u16);
+        assert_eq!(e.into_either(), Either::Left(8u16));
+
+        let e = MaybeEither::<u32, [u8; 32]>::new_right([1; 32]);
+        assert!(e.is_right());
+        let raw = e.into_raw();
+        assert_eq!(raw as usize & 1, 1);
+        let mut e = unsafe { MaybeEither::<u32, [u8; 32]>::from_raw(raw) };
+        if let Either::Right(a) = e.as_mut() {
+            a[31] = 2;
+        }
+        let e = e.map_right(|a| a[31]);
+        assert_eq!(e.as_ref(), Either::Right(&2));
+    }
+
+    #[test]
+    fn boxed_raw() {
+        // A struct with padding and a `String`, which isn't `NoUninit`.
+        struct Connection {
+            addr: String,
+            port: u16,
+        }
+
+        let conn = Connection { addr: String::from("localhost"), port: 80 };
+        let raw = MaybeEither::<u32, Connection>::new_right(conn).into_raw_boxed_right();
+        let e = unsafe { MaybeEither::<u32, Connection>::from_raw(raw) };
+        match e.as_ref() {
+            Either::Right(c) => assert_eq!((&c.addr[..], c.port), ("localhost", 80)),
+            Either::Left(_) => panic!("Unexpected!"),
+        };
+
+        let raw = MaybeEither::<u32, Connection>::new_left(7).into_raw_boxed_right();
+        let e = unsafe { MaybeEither::<u32, Connection>::from_raw(raw) };
+        match e.into_either() {
+            Either::Left(n) => assert_eq!(n, 7),
+            Either::Right(_) => panic!("Unexpected!"),
+        };
+
+        let raw = MaybeEither::<Connection, ()>::new_right(()).into_raw_boxed_left();
+        assert!(unsafe { MaybeEither::<Connection, ()>::from_raw(raw) }.is_right());
+
+        let raw = MaybeEither::<String, Vec<u8>>::new_left(String::from("a")).into_boxed_raw();
+        let e = unsafe { MaybeEither::<String, Vec<u8>>::from_raw(raw) };
+        assert_eq!(e.into_either(), Either::Left(String::from("a")));
+
+        // The tag byte doesn't leave room for a `u64`, which `MaybeBox` would store inline.
+        type Word = MaybeEither<u64, ()>;
+        type Ref = MaybeEither<u16, &'static u32>;
+        const { assert!(!Word::LEFT_INLINE && Word::RIGHT_INLINE) };
+        const { assert!(Ref::LEFT_INLINE && !Ref::RIGHT_INLINE) };
+        let e = MaybeEither::<u64, ()>::new_left(5);
+        let raw = e.into_raw();
+        assert_eq!(unsafe { *(raw.map_addr(|addr| addr & !1) as *const u64) }, 5);
+        drop(unsafe { MaybeEither::<u64, ()>::from_raw(raw) });
+    }
+
+    #[test]
+    fn all_layouts() {
+        let e = MaybeEither::<(), [u8; 7]>::from(Either::Right([3; 7]));
+        assert_eq!(e.into_either(), Either::Right([3; 7]));
+        let e = MaybeEither::<(), [u8; 7]>::new_left(());
+        assert_eq!(e.into_either(), Either::Left(()));
+        let e = MaybeEither::<[u8; 9], u64>::new_left([4; 9]);
+        assert_eq!(e.as_ref(), Either::Left(&[4; 9]));
+        let e = MaybeEither::<[u8; 9], u64>::new_right(u64::MAX);
+        assert_eq!(e.as_ref(), Either::Right(&u64::MAX));
+        let e = MaybeEither::<bool, ()>::new_right(());
+        assert!(e.is_right());
+    }
+
+    #[test]
+    fn drops() {
+        let rc = Rc::new(());
+        let e = MaybeEither::<Rc<()>, (Rc<()>, u64)>::new_left(rc.clone());
+        let f = MaybeEither::<Rc<()>, (Rc<()>, u64)>::new_right((rc.clone(), 0));
+        assert_eq!(Rc::strong_count(&rc), 3);
+        drop((e, f));
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+}
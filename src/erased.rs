@@ -0,0 +1,333 @@
+use std::any::{self, TypeId};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::ptr;
+
+use MaybeBox;
+
+/// A `MaybeBox<T>` with its type erased, so that `MaybeBox`es of different types can be stored
+/// together. This is the `MaybeBox`'s word along with a reference to a static table of functions
+/// for operating on the `T`, and can be turned back into a `MaybeBox<T>` with `downcast`.
+///
+/// Which operations are available depends on how the `ErasedMaybeBox` was constructed: it can
+/// always be dropped and have its type queried, but it can only be cloned if it was made with
+/// `new_clone` or `new_clone_debug` and only shows its value when debug-formatted if it was made
+/// with `new_debug` or `new_clone_debug`.
+///
+/// The type of the stored value is forgotten, so an `ErasedMaybeBox` is neither `Send` nor `Sync`:
+///
+/// ```compile_fail
+/// # use maybe_box::{MaybeBox, ErasedMaybeBox};
+/// # use std::rc::Rc;
+/// fn is_send<T: Send>(_: &T) {}
+/// is_send(&ErasedMaybeBox::new(MaybeBox::new(Rc::new(1u32))));
+/// ```
+///
+/// This has the same layout as the `maybe_box_erased` struct declared in `include/maybe_box.h`.
+#[repr(C)]
+pub struct ErasedMaybeBox {
+    // This is the `MaybeBox<T>`'s storage rather than the word returned by `into_raw`, since an
+    // inline `T` may leave some of its bytes uninitialized.
+    data: Data,
+    vtable: &'static VTable,
+    // The erased value might not be `Send` or `Sync`. This is zero-sized, so the layout is the
+    // same as the C struct's.
+    _ph: PhantomData<*mut ()>,
+}
+
+/// The storage of the erased `MaybeBox<T>`.
+type Data = MaybeUninit<usize>;
+
+/// The operations on the type stored in an `ErasedMaybeBox`.
+struct VTable {
+    drop: unsafe fn(&mut Data),
+    type_id: fn() -> TypeId,
+    type_name: fn() -> &'static str,
+    clone: Option<unsafe fn(&Data) -> Data>,
+    debug: Option<unsafe fn(&Data, &mut fmt::Formatter) -> fmt::Result>,
+}
+
+/// Reinterpret a reference to the storage of a `MaybeBox<T>` as a reference to the `MaybeBox<T>`
+/// itself.
+unsafe fn as_maybe_box<T>(data: &Data) -> &MaybeBox<T> {
+    &*(data as *const Data as *const MaybeBox<T>)
+}
+
+unsafe fn as_maybe_box_mut<T>(data: &mut Data) -> &mut MaybeBox<T> {
+    &mut *(data as *mut Data as *mut MaybeBox<T>)
+}
+
+unsafe fn drop_data<T>(data: &mut Data) {
+    drop(MaybeBox::<T>::from_data(ptr::read(data)));
+}
+
+unsafe fn clone_data<T: Clone>(data: &Data) -> Data {
+    let t: &T = as_maybe_box::<T>(data);
+    MaybeBox::new(t.clone()).into_data()
+}
+
+unsafe fn debug_data<T: fmt::Debug>(data: &Data, f: &mut fmt::Formatter) -> fmt::Result {
+    let t: &T = as_maybe_box::<T>(data);
+    t.fmt(f)
+}
+
+struct VTableFor<T>(PhantomData<T>);
+
+impl<T: 'static> VTableFor<T> {
+    const PLAIN: &'static VTable = &VTable {
+        drop: drop_data::<T>,
+        type_id: TypeId::of::<T>,
+        type_name: any::type_name::<T>,
+        clone: None,
+        debug: None,
+    };
+}
+
+impl<T: Clone + 'static> VTableFor<T> {
+    const CLONE: &'static VTable = &VTable {
+        drop: drop_data::<T>,
+        type_id: TypeId::of::<T>,
+        type_name: any::type_name::<T>,
+        clone: Some(clone_data::<T>),
+        debug: None,
+    };
+}
+
+impl<T: fmt::Debug + 'static> VTableFor<T> {
+    const DEBUG: &'static VTable = &VTable {
+        drop: drop_data::<T>,
+        type_id: TypeId::of::<T>,
+        type_name: any::type_name::<T>,
+        clone: None,
+        debug: Some(debug_data::<T>),
+    };
+}
+
+impl<T: Clone + fmt::Debug + 'static> VTableFor<T> {
+    const CLONE_DEBUG: &'static VTable = &VTable {
+        drop: drop_data::<T>,
+        type_id: TypeId::of::<T>,
+        type_name: any::type_name::<T>,
+        clone: Some(clone_data::<T>),
+        debug: Some(debug_data::<T>),
+    };
+}
+
+/// The error returned when `ErasedMaybeBox::downcast` is called with the wrong type. Holds the
+/// `ErasedMaybeBox` so that it isn't lost.
+#[derive(Debug)]
+pub struct DowncastError(pub ErasedMaybeBox);
+
+impl fmt::Display for DowncastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ErasedMaybeBox holds a {}", self.0.type_name())
+    }
+}
+
+impl ::std::error::Error for DowncastError {}
+
+impl ErasedMaybeBox {
+    fn with_vtable<T>(mb: MaybeBox<T>, vtable: &'static VTable) -> ErasedMaybeBox {
+        ErasedMaybeBox {
+            data: mb.into_data(),
+            vtable,
+            _ph: PhantomData,
+        }
+    }
+
+    /// Erase the type of a `MaybeBox<T>`.
+    pub fn new<T: 'static>(mb: MaybeBox<T>) -> ErasedMaybeBox {
+        ErasedMaybeBox::with_vtable(mb, VTableFor::<T>::PLAIN)
+    }
+
+    /// Erase the type of a `MaybeBox<T>`, keeping the ability to clone it.
+    pub fn new_clone<T: Clone + 'static>(mb: MaybeBox<T>) -> ErasedMaybeBox {
+        ErasedMaybeBox::with_vtable(mb, VTableFor::<T>::CLONE)
+    }
+
+    /// Erase the type of a `MaybeBox<T>`, keeping the ability to debug-format it.
+    pub fn new_debug<T: fmt::Debug + 'static>(mb: MaybeBox<T>) -> ErasedMaybeBox {
+        ErasedMaybeBox::with_vtable(mb, VTableFor::<T>::DEBUG)
+    }
+
+    /// Erase the type of a `MaybeBox<T>`, keeping the ability to clone and debug-format it.
+    pub fn new_clone_debug<T: Clone + fmt::Debug + 'static>(mb: MaybeBox<T>) -> ErasedMaybeBox {
+        ErasedMaybeBox::with_vtable(mb, VTableFor::<T>::CLONE_DEBUG)
+    }
+
+    /// The `TypeId` of the stored value.
+    pub fn type_id(&self) -> TypeId {
+        (self.vtable.type_id)()
+    }
+
+    /// The name of the stored value's type, as given by `std::any::type_name`.
+    pub fn type_name(&self) -> &'static str {
+        (self.vtable.type_name)()
+    }
+
+    /// Whether the stored value is a `T`.
+    pub fn is<T: 'static>(&self) -> bool {
+        self.type_id() == TypeId::of::<T>()
+    }
+
+    /// Whether this `ErasedMaybeBox` can be cloned with `try_clone`.
+    pub fn is_cloneable(&self) -> bool {
+        self.vtable.clone.is_some()
+    }
+
+    /// Clone the stored value, if this `ErasedMaybeBox` was constructed with the ability to do
+    /// so.
+    pub fn try_clone(&self) -> Option<ErasedMaybeBox> {
+        self.vtable.clone.map(|clone| ErasedMaybeBox {
+            data: unsafe { clone(&self.data) },
+            vtable: self.vtable,
+            _ph: PhantomData,
+        })
+    }
+
+    /// Recover the original `MaybeBox<T>`, or return `self` if the stored value isn't a `T`.
+    pub fn downcast<T: 'static>(self) -> Result<MaybeBox<T>, DowncastError> {
+        if self.is::<T>() {
+            let this = ManuallyDrop::new(self);
+            Ok(unsafe { MaybeBox::from_data(ptr::read(&this.data)) })
+        } else {
+            Err(DowncastError(self))
+        }
+    }
+
+    /// Borrow the stored value if it's a `T`.
+    pub fn downcast_ref<T: 'static>(&self) -> Option<&T> {
+        if self.is::<T>() {
+            Some(unsafe { as_maybe_box::<T>(&self.data) })
+        } else {
+            None
+        }
+    }
+
+    /// Mutably borrow the stored value if it's a `T`.
+    pub fn downcast_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        if self.is::<T>() {
+            Some(unsafe { as_maybe_box_mut::<T>(&mut self.data) })
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for ErasedMaybeBox {
+    fn drop(&mut self) {
+        unsafe { (self.vtable.drop)(&mut self.data) }
+    }
+}
+
+impl<T: 'static> From<MaybeBox<T>> for ErasedMaybeBox {
+    fn from(mb: MaybeBox<T>) -> ErasedMaybeBox {
+        ErasedMaybeBox::new(mb)
+    }
+}
+
+impl fmt::Debug for ErasedMaybeBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        struct Inner<'a>(&'a ErasedMaybeBox);
+
+        impl<'a> fmt::Debug for Inner<'a> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self.0.vtable.debug {
+                    Some(debug) => unsafe { debug(&self.0.data, f) },
+                    None => write!(f, "<{}>", self.0.type_name()),
+                }
+            }
+        }
+
+        f.debug_tuple("ErasedMaybeBox").field(&Inner(self)).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+    use std::rc::Rc;
+
+    #[test]
+    fn layout() {
+        // This must match `maybe_box_erased` in `include/maybe_box.h`.
+        assert_eq!(mem::size_of::<ErasedMaybeBox>(), 2 * mem::size_of::<usize>());
+        assert_eq!(mem::offset_of!(ErasedMaybeBox, data), 0);
+        assert_eq!(mem::offset_of!(ErasedMaybeBox, vtable), mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn downcast() {
+        let slots = vec![
+            ErasedMaybeBox::new(MaybeBox::new(123u32)),
+            ErasedMaybeBox::from(MaybeBox::new(String::from("hello"))),
+            ErasedMaybeBox::new(MaybeBox::new(())),
+        ];
+        assert!(slots[0].is::<u32>());
+        assert!(!slots[0].is::<u64>());
+        assert_eq!(slots[1].type_name(), any::type_name::<String>());
+        assert_eq!(slots[2].type_id(), TypeId::of::<()>());
+
+        let mut slots = slots.into_iter();
+        let erased = slots.next().unwrap();
+        let erased = erased.downcast::<i32>().unwrap_err().0;
+        assert_eq!(erased.downcast::<u32>().unwrap().into_inner(), 123);
+
+        let mut erased = slots.next().unwrap();
+        assert_eq!(erased.downcast_ref::<String>().map(|s| &s[..]), Some("hello"));
+        assert!(erased.downcast_ref::<&str>().is_none());
+        erased.downcast_mut::<String>().unwrap().push_str(" world");
+        let err = erased.downcast::<Vec<u8>>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("ErasedMaybeBox holds a {}", any::type_name::<String>()),
+        );
+        assert_eq!(err.0.downcast::<String>().unwrap().into_inner(), "hello world");
+    }
+
+    #[test]
+    fn clone_and_debug() {
+        let rc = Rc::new(5u32);
+
+        let erased = ErasedMaybeBox::new(MaybeBox::new(rc.clone()));
+        assert!(!erased.is_cloneable());
+        assert!(erased.try_clone().is_none());
+        assert_eq!(
+            format!("{:?}", erased),
+            format!("ErasedMaybeBox(<{}>)", any::type_name::<Rc<u32>>()),
+        );
+        assert_eq!(Rc::strong_count(&rc), 2);
+        drop(erased);
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let erased = ErasedMaybeBox::new_clone(MaybeBox::new(rc.clone()));
+        let cloned = erased.try_clone().unwrap();
+        assert_eq!(Rc::strong_count(&rc), 3);
+        assert_eq!(**cloned.downcast_ref::<Rc<u32>>().unwrap(), 5);
+        drop((erased, cloned));
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let erased = ErasedMaybeBox::new_debug(MaybeBox::new(vec![1u64, 2, 3]));
+        assert!(erased.try_clone().is_none());
+        assert_eq!(format!("{:?}", erased), "ErasedMaybeBox([1, 2, 3])");
+
+        let erased = ErasedMaybeBox::new_clone_debug(MaybeBox::new(true));
+        let cloned = erased.try_clone().unwrap();
+        assert_eq!(format!("{:?}", cloned), "ErasedMaybeBox(true)");
+    }
+
+    #[test]
+    fn uninit_bytes() {
+        // Inline values with padding or unused bytes are never read as a pointer.
+        let erased = ErasedMaybeBox::new_clone_debug(MaybeBox::new((1u8, 2u16)));
+        let cloned = erased.try_clone().unwrap();
+        assert_eq!(format!("{:?}", cloned), "ErasedMaybeBox((1, 2))");
+        assert_eq!(erased.downcast::<(u8, u16)>().unwrap().into_inner(), (1, 2));
+
+        let mut erased = ErasedMaybeBox::new(MaybeBox::new(None::<u16>));
+        *erased.downcast_mut::<Option<u16>>().unwrap() = Some(3);
+        assert_eq!(erased.downcast::<Option<u16>>().unwrap().into_inner(), Some(3));
+    }
+}
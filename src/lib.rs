@@ -1,195 +1,913 @@
 //! Store arbitrary data in the size of a `usize`, only boxing it if necessary.
+//!
+//! # Raw words
+//!
+//! Most of the types in this crate can be converted into the word they're stored in, with a method
+//! such as `MaybeBox::into_raw`, for passing to C code as a `void *`, and back again with the
+//! matching method, such as `MaybeBox::from_raw`. The word owns the value, so to avoid a leak it
+//! must eventually be converted back into the type it came from. Since that transfers ownership
+//! back, each word can only be converted back once.
+//!
+//! A value stored inline is part of the word, so its bytes are read along with the rest of the
+//! word. That's undefined behaviour if any of them are uninitialized, such as padding bytes, so
+//! methods which return the word require the types of inline values to implement `NoUninit`.
+//!
+//! A value that's too big to be stored inline is only ever represented by a pointer, so none of
+//! its bytes are read. Types such as `MaybeBox` have a method such as `MaybeBox::into_boxed_raw`
+//! which works for any such type, whether or not it implements `NoUninit`, and fails to compile
+//! if the value would be stored inline.
 
-use std::mem;
-use std::ptr;
+use std::alloc::{self, Layout};
+use std::mem::{self, MaybeUninit, ManuallyDrop};
+use std::ptr::{self, NonNull};
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::fmt;
 use std::hash;
+use std::os::raw::c_void;
+
+#[cfg(feature = "allocator")]
+extern crate allocator_api2;
+#[cfg(feature = "arena")]
+extern crate bumpalo;
+#[cfg(feature = "derive")]
+extern crate maybe_box_derive;
+
+#[cfg(feature = "allocator")]
+pub mod allocator;
+#[cfg(feature = "arena")]
+pub mod arena;
+pub mod callback;
+mod carrier;
+mod dealloc;
+mod dyn_box;
+mod either;
+mod erased;
+mod non_null;
+mod no_uninit;
+mod option;
+pub mod pool;
+mod slice_box;
+mod small_str;
+mod tagged;
+pub mod word_enum;
+#[cfg(feature = "capi")]
+pub mod capi;
+
+pub use carrier::Carrier;
+use dealloc::{BoxAlloc, Global};
+pub use dyn_box::MaybeBoxDyn;
+pub use either::{MaybeEither, Either};
+pub use erased::{ErasedMaybeBox, DowncastError};
+pub use non_null::{NonNullMaybeBox, NeverZero};
+pub use no_uninit::NoUninit;
+pub use option::MaybeBoxOption;
+pub use pool::PooledMaybeBox;
+pub use slice_box::MaybeBoxSlice;
+pub use small_str::MaybeBoxStr;
+pub use tagged::TaggedMaybeBox;
+pub use word_enum::WordEnum;
+#[cfg(feature = "derive")]
+pub use maybe_box_derive::WordEnum;
 
 /// Hold a value of type `T` in the space for a `usize`, only boxing it if necessary.
 /// This can be a useful optimization when dealing with C APIs that allow you to pass around some
 /// arbitrary `void *`-sized piece of data.
 ///
 /// This type is guranteed to be the same size as a `usize`.
-pub struct MaybeBox<T> {
-    data: usize,
+///
+/// Zero-sized types are never boxed and never allocate, regardless of their alignment. The word
+/// holding a zero-sized `T` contains `align_of::<T>()`, the same dangling address as
+/// `NonNull::<T>::dangling()`.
+///
+/// With the `allocator` feature, boxed values can be allocated with a custom allocator `A`
+/// instead of the global one. See the `allocator` module.
+pub type MaybeBox<T, A = Global> = MaybeBoxIn<T, usize, A>;
+
+/// Hold a value of type `T` in the space for a `C`, only boxing it if necessary. `MaybeBox<T>` is
+/// `MaybeBoxIn<T, usize>`; see `Carrier` for the other types that can be used.
+///
+/// This type is guaranteed to be the same size as a `C`. Zero-sized types are never boxed and
+/// never allocate. If the carrier has room for a pointer then it holds `align_of::<T>()` for a
+/// zero-sized `T`, otherwise it's zero.
+///
+/// `MaybeBox<T>` is an alias rather than this type with a default of `usize` for `C`, because
+/// default type parameters aren't used for inference: with `MaybeBoxIn<T, C = usize>`, calling
+/// `MaybeBoxIn::new(x)` would fail to compile without naming the carrier, since the compiler
+/// can't tell which `C` is wanted. The aliases fix the carrier, so `MaybeBox::new(x)`,
+/// `MaybeBox64::new(x)` and so on only have `T` left to infer.
+///
+/// `A` is the allocator that boxed values are allocated with. It's stored alongside the carrier,
+/// so the `MaybeBoxIn<T, C, A>` is only the same size as a `C` if `A` is zero-sized, as the
+/// default, `Global`, is. Other allocators can only be used with the `allocator` feature.
+#[repr(C)]
+pub struct MaybeBoxIn<T, C: Carrier, A: BoxAlloc = Global> {
+    // This is a `MaybeUninit<C>`, so the pointer to a boxed value keeps its provenance even
+    // though `C` is typically an integer type, unless `C::NON_NULL` is set. It's accessed as a
+    // `MaybeUninit<C>` through `data` and `data_mut`. It comes first so that a raw word can be
+    // reinterpreted as a `MaybeBox<T>`.
+    data: C::Storage,
+    alloc: ManuallyDrop<A>,
     _ph: PhantomData<T>,
 }
 
+/// Hold a value of type `T` in the space for `WORDS` `usize`s, only boxing it if necessary.
+/// This can be used to avoid allocating for bigger types, eg. to fill a 16-byte field of a C
+/// struct.
+///
+/// `WORDS` must be at least 1, since there needs to be space for a pointer to a boxed `T`.
+pub type MaybeBoxN<T, const WORDS: usize> = MaybeBoxIn<T, [usize; WORDS]>;
+
+/// Hold a value of type `T` in the space for a `u64`, only boxing it if necessary. Unlike
+/// `MaybeBox<T>`, which values are stored inline doesn't depend on the target's pointer width.
+/// This is useful for 64-bit slots in C APIs, such as `epoll_data_t`'s `u64` or io_uring's
+/// `user_data`.
+pub type MaybeBox64<T> = MaybeBoxIn<T, u64>;
+
+/// Hold a value of type `T` in the space for a `u32`, only boxing it if necessary.
+///
+/// On targets with 64-bit pointers there isn't room to store a pointer in a `u32`, so it's a
+/// compile-time error to create a `MaybeBox32<T>` where `T` doesn't fit inline.
+pub type MaybeBox32<T> = MaybeBoxIn<T, u32>;
+
+unsafe impl<T: Send, C: Carrier, A: BoxAlloc + Send> Send for MaybeBoxIn<T, C, A> {}
+unsafe impl<T: Sync, C: Carrier, A: BoxAlloc + Sync> Sync for MaybeBoxIn<T, C, A> {}
+
 #[inline]
-unsafe fn transmogrify_inline<'a, T>(ptr: &'a usize) -> &'a T {
-    mem::transmute(ptr)
+const fn is_zst<T>() -> bool {
+    mem::size_of::<T>() == 0
 }
 
+/// Whether an `S` has room for a pointer.
 #[inline]
-unsafe fn transmogrify_inline_mut<'a, T>(ptr: &'a mut usize) -> &'a mut T {
-    mem::transmute(ptr)
+const fn holds_pointer<S>() -> bool {
+    mem::size_of::<S>() >= mem::size_of::<*mut ()>() &&
+    mem::align_of::<S>() >= mem::align_of::<*mut ()>()
 }
 
+/// Whether a `T` can be stored directly in a `C`. This requires both that it's small enough and
+/// that the `C` is sufficiently aligned for it, and that the `C` doesn't forbid being zeroed.
+/// Zero-sized types always fit since they don't actually occupy the `C` at all.
 #[inline]
-unsafe fn transmogrify_boxed<'a, T>(ptr: &'a usize) -> &'a Box<T> {
-    mem::transmute(ptr)
+const fn fits_inline<T, C: Carrier>() -> bool {
+    is_zst::<T>() || (
+        !C::NON_NULL &&
+        mem::size_of::<T>() <= mem::size_of::<C>() &&
+        mem::align_of::<T>() <= mem::align_of::<C>()
+    )
 }
 
+/// Whether the provenance of the pointers in an inline `T` has to be exposed when it's handed out
+/// as a `C`. A `C` that doesn't carry provenance would lose the provenance of any pointer in the
+/// `T`. A `C` without room for a pointer is too small for a `T` holding one.
 #[inline]
-unsafe fn transmogrify_boxed_mut<'a, T>(ptr: &'a mut usize) -> &'a mut Box<T> {
-    mem::transmute(ptr)
+const fn exposes_inline<T: NoUninit, C: Carrier>() -> bool {
+    !C::CARRIES_PROVENANCE && T::HOLDS_POINTERS && holds_pointer::<C>() &&
+    !is_zst::<T>() && fits_inline::<T, C>()
 }
 
-unsafe fn new_inline<'a, T>(t: T, ptr: &'a mut usize) {
-    let ptr = transmogrify_inline_mut(ptr);
-    ptr::write(ptr, t);
+/// Expose the provenance of each pointer-sized chunk of the inline `T` at the start of `data`,
+/// replacing it with the same bytes as an integer. Pointers are aligned, so any pointer in the `T`
+/// is one of these chunks. The bytes themselves don't change.
+#[inline]
+unsafe fn expose_inline<T, C: Carrier>(data: &mut MaybeUninit<C>) {
+    let words = data.as_mut_ptr() as *mut *const u8;
+    for i in 0..mem::size_of::<T>() / mem::size_of::<usize>() {
+        let ptr = ptr::read(words.add(i));
+        ptr::write(words.add(i) as *mut usize, ptr.expose_provenance());
+    }
 }
 
-unsafe fn new_boxed<'a, T>(t: T, ptr: &'a mut usize) {
-    let ptr = transmogrify_boxed_mut(ptr);
-    ptr::write(ptr, Box::new(t));
+/// Undo `expose_inline`, turning each pointer-sized chunk of the inline `T` at the start of `data`
+/// back into a pointer with any provenance that was exposed for its address.
+#[inline]
+unsafe fn recover_inline<T, C: Carrier>(data: &mut MaybeUninit<C>) {
+    let words = data.as_mut_ptr() as *mut *const u8;
+    for i in 0..mem::size_of::<T>() / mem::size_of::<usize>() {
+        let addr = ptr::read(words.add(i) as *const usize);
+        ptr::write(words.add(i), ptr::with_exposed_provenance::<u8>(addr));
+    }
 }
 
-unsafe fn get_inline<'a, T>(ptr: &'a mut usize) -> T {
-    let ptr = transmogrify_inline_mut(ptr);
-    let t: T = ptr::read(ptr);
-    t
+/// Replace the pointer to a boxed `T` at the start of `data` with its address, exposing its
+/// provenance, if `C` doesn't carry provenance itself.
+#[inline]
+unsafe fn expose_boxed<T, C: Carrier>(data: &mut MaybeUninit<C>) {
+    if !C::CARRIES_PROVENANCE {
+        let ptr = ptr::read(data.as_ptr() as *const *mut T);
+        ptr::write(data.as_mut_ptr() as *mut usize, ptr.expose_provenance());
+    }
 }
 
-unsafe fn get_boxed<'a, T>(ptr: &'a mut usize) -> Box<T> {
-    let ptr = transmogrify_boxed_mut(ptr);
-    let b: Box<T> = ptr::read(ptr);
-    b
+/// Undo `expose_boxed`, turning the address at the start of `data` back into a pointer to a boxed
+/// `T`.
+#[inline]
+unsafe fn recover_boxed<T, C: Carrier>(data: &mut MaybeUninit<C>) {
+    if !C::CARRIES_PROVENANCE {
+        let addr = ptr::read(data.as_ptr() as *const usize);
+        let ptr: *mut T = ptr::with_exposed_provenance_mut(addr);
+        ptr::write(data.as_mut_ptr() as *mut *mut T, ptr);
+    }
+}
+
+/// Store `t` in `data`, either directly or as a pointer to a boxed `T` at the start of `data`.
+#[inline]
+unsafe fn write_data<T, C: Carrier>(t: T, data: &mut MaybeUninit<C>) {
+    if is_zst::<T>() {
+        let ptr = NonNull::<T>::dangling().as_ptr();
+        ptr::write(ptr, t);
+        if holds_pointer::<C>() {
+            ptr::write(data.as_mut_ptr() as *mut *mut T, ptr);
+        }
+    } else if fits_inline::<T, C>() {
+        ptr::write(data.as_mut_ptr() as *mut T, t);
+    } else {
+        ptr::write(data.as_mut_ptr() as *mut *mut T, Box::into_raw(Box::new(t)));
+    }
+}
+
+/// Get a pointer to the `T` stored in `data`. `data` must have been initialized with `write_data`.
+#[inline]
+unsafe fn data_ptr<T, C: Carrier>(data: &MaybeUninit<C>) -> *const T {
+    if is_zst::<T>() {
+        NonNull::dangling().as_ptr()
+    } else if fits_inline::<T, C>() {
+        data.as_ptr() as *const T
+    } else {
+        ptr::read(data.as_ptr() as *const *const T)
+    }
+}
+
+/// Get a mutable pointer to the `T` stored in `data`. `data` must have been initialized with
+/// `write_data`.
+#[inline]
+unsafe fn data_ptr_mut<T, C: Carrier>(data: &mut MaybeUninit<C>) -> *mut T {
+    if is_zst::<T>() {
+        NonNull::dangling().as_ptr()
+    } else if fits_inline::<T, C>() {
+        data.as_mut_ptr() as *mut T
+    } else {
+        ptr::read(data.as_ptr() as *const *mut T)
+    }
+}
+
+/// Move the `T` out of `data`. `data` must have been initialized with `write_data` and must be
+/// treated as uninitialized afterwards.
+#[inline]
+unsafe fn read_data<T, C: Carrier>(data: &mut MaybeUninit<C>) -> Unpacked<T> {
+    if fits_inline::<T, C>() {
+        Unpacked::Inline(ptr::read(data_ptr::<T, C>(data)))
+    } else {
+        Unpacked::Boxed(Box::from_raw(ptr::read(data.as_ptr() as *const *mut T)))
+    }
+}
+
+/// Read the word at `word`, which holds a `T` either inline or as a pointer to a boxed one, as a
+/// `W` to hand it out as a raw word. An inline `T` is read as part of the word, which is why this
+/// requires `T: NoUninit`.
+///
+/// The word must have been zeroed before the `T` or the pointer was written to it, so that all of
+/// its other bytes are initialized.
+#[inline]
+#[allow(clippy::extra_unused_type_parameters)] // `T` is only there for its bound.
+unsafe fn read_raw<T: NoUninit, W>(word: *const W) -> W {
+    ptr::read(word)
+}
+
+/// Read the word at `word` as a `W`, like `read_raw`, for a value that's never stored inline. The
+/// caller must check at compile time that the value's type is either zero-sized or too big to be
+/// stored inline, so that the word is just a pointer or an address.
+#[inline]
+unsafe fn read_boxed_raw<W>(word: *const W) -> W {
+    ptr::read(word)
+}
+
+/// Get the storage for a raw word returned by `read_raw` or `read_boxed_raw`.
+#[inline]
+fn data_from_raw(raw: *mut c_void) -> MaybeUninit<usize> {
+    let mut data = MaybeUninit::<usize>::uninit();
+    unsafe { ptr::write(data.as_mut_ptr() as *mut *mut c_void, raw) };
+    data
 }
 
 /// An unpacked `MaybeBox<T>`. Produced by `MaybeBox::unpack`.
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Unpacked<T> {
-    /// A `T` stored inline.
+    /// A `T` stored inline. Zero-sized types are always unpacked as `Inline`.
     Inline(T),
     /// A `T` stored in a `Box`.
     Boxed(Box<T>),
 }
 
-impl<T> MaybeBox<T> {
-    /// Wrap a `T` into a `MaybeBox<T>`. This will allocate if
-    /// `size_of::<T>() > size_of::<usize>()`.
+/// The error returned by `MaybeBox::try_new` and `MaybeBox::try_new_with`, and by
+/// `MaybeBoxIn::try_new_in` with the `allocator` feature, when boxing the value fails to
+/// allocate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "memory allocation failed")
+    }
+}
+
+impl ::std::error::Error for AllocError {}
+
+/// Frees an allocation when dropped. This makes sure it's freed even if a value being constructed
+/// or dropped in it panics.
+struct DeallocOnDrop<'a, A: BoxAlloc + 'a> {
+    ptr: *mut u8,
+    layout: Layout,
+    alloc: &'a A,
+}
+
+impl<'a, A: BoxAlloc> Drop for DeallocOnDrop<'a, A> {
+    fn drop(&mut self) {
+        unsafe { self.alloc.dealloc(self.ptr, self.layout) }
+    }
+}
+
+impl<T, C: Carrier> MaybeBoxIn<T, C> {
+    /// Fails to compile if a `T` needs boxing but a `C` can't hold the pointer.
+    const CAN_HOLD: () = assert!(
+        fits_inline::<T, C>() || holds_pointer::<C>(),
+        "value doesn't fit inline and the carrier can't hold a pointer",
+    );
+
+    /// Wrap a `T` into a `MaybeBoxIn<T, C>`. This will allocate if `size_of::<T>() > size_of::<C>()`
+    /// or if `T` requires a greater alignment than `C`. Zero-sized types never allocate.
+    ///
+    /// If `T` needs to be boxed but `C` doesn't have room for a pointer this fails to compile.
     #[inline]
-    pub fn new(t: T) -> MaybeBox<T> {
-        let mut new: MaybeBox<T> = unsafe { mem::uninitialized() };
+    pub fn new(t: T) -> MaybeBoxIn<T, C> {
+        let () = MaybeBoxIn::<T, C>::CAN_HOLD;
+
+        // Zero the storage first so that bytes not covered by an inline `T` have a defined value.
+        let mut data = MaybeUninit::zeroed();
         unsafe {
-            {
-                let ptr = &mut new.data;
-                if mem::size_of::<T>() <= mem::size_of::<usize>() {
-                    new_inline::<T>(t, ptr)
-                } else {
-                    new_boxed::<T>(t, ptr)
-                };
-            }
-            new
+            write_data(t, &mut data);
+            MaybeBoxIn::from_data(data)
         }
     }
 
-    /// Consume the `MaybeBox<T>` and return the inner `T`.
-    pub fn into_inner(mut self) -> T {
-        let ret = self.get_inner();
-        mem::forget(self);
-        ret
+    /// Wrap a `T` into a `MaybeBoxIn<T, C>` like `new`, but if boxing it fails to allocate then
+    /// return `t` along with an error rather than aborting.
+    pub fn try_new(t: T) -> Result<MaybeBoxIn<T, C>, (T, AllocError)> {
+        let () = MaybeBoxIn::<T, C>::CAN_HOLD;
+        if fits_inline::<T, C>() {
+            return Ok(MaybeBoxIn::new(t));
+        }
+        let ptr = match MaybeBoxIn::<T, C>::try_alloc() {
+            Ok(ptr) => ptr,
+            Err(e) => return Err((t, e)),
+        };
+        unsafe {
+            ptr::write(ptr, t);
+            Ok(MaybeBoxIn::from_box_ptr(ptr))
+        }
     }
 
-    /// Consume the `MaybeBox<T>` and return the inner `T`, possibly boxed (if
+    /// Wrap the `T` returned by `f` into a `MaybeBoxIn<T, C>`, returning an error rather than
+    /// aborting if boxing it fails to allocate. If the `T` needs boxing then the allocation is
+    /// made before calling `f`, so `f` isn't called if it fails.
+    pub fn try_new_with<F: FnOnce() -> T>(f: F) -> Result<MaybeBoxIn<T, C>, AllocError> {
+        let () = MaybeBoxIn::<T, C>::CAN_HOLD;
+        if fits_inline::<T, C>() {
+            return Ok(MaybeBoxIn::new(f()));
+        }
+        let ptr = MaybeBoxIn::<T, C>::try_alloc()?;
+        let guard = DeallocOnDrop {
+            ptr: ptr as *mut u8,
+            layout: Layout::new::<T>(),
+            alloc: &Global,
+        };
+        unsafe {
+            ptr::write(ptr, f());
+            mem::forget(guard);
+            Ok(MaybeBoxIn::from_box_ptr(ptr))
+        }
+    }
+
+    /// Allocate space for a boxed `T`, which must not be zero-sized.
+    #[inline]
+    fn try_alloc() -> Result<*mut T, AllocError> {
+        let ptr = unsafe { alloc::alloc(Layout::new::<T>()) } as *mut T;
+        if ptr.is_null() {
+            Err(AllocError)
+        } else {
+            Ok(ptr)
+        }
+    }
+
+    /// Make a `MaybeBoxIn<T, C>` holding a boxed `T` from a pointer to its allocation.
+    #[inline]
+    unsafe fn from_box_ptr(ptr: *mut T) -> MaybeBoxIn<T, C> {
+        let mut data = MaybeUninit::zeroed();
+        ptr::write(data.as_mut_ptr() as *mut *mut T, ptr);
+        MaybeBoxIn::from_data(data)
+    }
+
+    /// Take the storage out of the `MaybeBoxIn<T, C>` without reading it as a `C`, so any
+    /// uninitialized bytes of an inline `T` stay that way.
+    #[inline]
+    pub(crate) fn into_data(self) -> MaybeUninit<C> {
+        let this = ManuallyDrop::new(self);
+        unsafe { ptr::read(this.data()) }
+    }
+
+    /// Reconstruct a `MaybeBoxIn<T, C>` from storage returned by `into_data`.
+    #[inline]
+    pub(crate) unsafe fn from_data(data: MaybeUninit<C>) -> MaybeBoxIn<T, C> {
+        MaybeBoxIn::from_data_in(data, Global)
+    }
+
+    /// Consume the `MaybeBoxIn<T, C>` and return the inner `T`, possibly boxed (if
     /// it was already).
     ///
     /// This may be more efficient than calling `into_inner` and then boxing
     /// the returned value.
-    pub fn unpack(mut self) -> Unpacked<T> {
-        let ret = {
-            let ptr = &mut self.data;
-            if mem::size_of::<T>() <= mem::size_of::<usize>() {
-                Unpacked::Inline(unsafe { get_inline::<T>(ptr) })
-            } else {
-                Unpacked::Boxed(unsafe { get_boxed::<T>(ptr) })
+    pub fn unpack(self) -> Unpacked<T> {
+        let mut this = ManuallyDrop::new(self);
+        unsafe { read_data(this.data_mut()) }
+    }
+
+    /// Consume the `MaybeBoxIn<T, C>` and return the carrier. Inline values are stored
+    /// bit-for-bit, with the bytes of the carrier not covered by the `T` set to zero. Boxed values
+    /// are represented by a pointer to their heap allocation at the start of the carrier.
+    ///
+    /// If `C` doesn't carry provenance then the pointer's provenance is exposed so that
+    /// `from_carrier` can recover it. So is the provenance of any pointer in an inline `T` that
+    /// might hold one, according to `NoUninit::HOLDS_POINTERS`. Inline values are never boxed, and
+    /// the carrier holds the same bytes either way.
+    ///
+    /// The carrier must eventually be turned back into a `MaybeBoxIn<T, C>` with `from_carrier`.
+    /// See the crate docs on raw words.
+    pub fn into_carrier(self) -> C
+        where T: NoUninit
+    {
+        let mut this = ManuallyDrop::new(self);
+        unsafe {
+            if exposes_inline::<T, C>() {
+                expose_inline::<T, C>(this.data_mut());
+            } else if !fits_inline::<T, C>() {
+                expose_boxed::<T, C>(this.data_mut());
             }
+            read_raw::<T, C>(this.data().as_ptr())
+        }
+    }
+
+    /// Reconstruct a `MaybeBoxIn<T, C>` from a carrier returned by `into_carrier`.
+    ///
+    /// # Safety
+    ///
+    /// `c` must have been returned by `MaybeBoxIn::<T, C>::into_carrier` for the same `T` and `C`,
+    /// and not already turned back into a `MaybeBoxIn<T, C>`.
+    pub unsafe fn from_carrier(c: C) -> MaybeBoxIn<T, C>
+        where T: NoUninit
+    {
+        let mut data = MaybeUninit::new(c);
+        if exposes_inline::<T, C>() {
+            recover_inline::<T, C>(&mut data);
+        } else if !fits_inline::<T, C>() {
+            recover_boxed::<T, C>(&mut data);
+        }
+        MaybeBoxIn::from_data(data)
+    }
+
+    /// Consume the `MaybeBoxIn<T, C>` and return the carrier, for a `T` that's too big to be
+    /// stored inline. The carrier is exactly as returned by `into_carrier`, but since the `T` is
+    /// never part of it, it doesn't need to implement `NoUninit`.
+    ///
+    /// Fails to compile if a `T` would be stored inline; use `into_carrier` for those.
+    ///
+    /// The carrier must eventually be turned back into a `MaybeBoxIn<T, C>` with
+    /// `from_boxed_carrier`. See the crate docs on raw words.
+    pub fn into_boxed_carrier(self) -> C {
+        const {
+            assert!(
+                is_zst::<T>() || !fits_inline::<T, C>(),
+                "value is stored inline, so `into_boxed_carrier` can't be used",
+            )
         };
-        mem::forget(self);
-        ret
+        let mut this = ManuallyDrop::new(self);
+        unsafe {
+            if !is_zst::<T>() {
+                expose_boxed::<T, C>(this.data_mut());
+            }
+            read_boxed_raw(this.data().as_ptr())
+        }
     }
 
-    fn get_inner(&mut self) -> T {
-        let ptr = &mut self.data;
-        if mem::size_of::<T>() <= mem::size_of::<usize>() {
-            unsafe { get_inline::<T>(ptr) }
-        } else {
-            *unsafe { get_boxed::<T>(ptr) }
+    /// Reconstruct a `MaybeBoxIn<T, C>` from a carrier returned by `into_boxed_carrier`, or by
+    /// `into_carrier` if `T` implements `NoUninit`.
+    ///
+    /// Fails to compile if a `T` would be stored inline.
+    ///
+    /// # Safety
+    ///
+    /// `c` must have been returned by `MaybeBoxIn::<T, C>::into_boxed_carrier` or
+    /// `MaybeBoxIn::<T, C>::into_carrier` for the same `T` and `C`, and not already turned back
+    /// into a `MaybeBoxIn<T, C>`.
+    pub unsafe fn from_boxed_carrier(c: C) -> MaybeBoxIn<T, C> {
+        const {
+            assert!(
+                is_zst::<T>() || !fits_inline::<T, C>(),
+                "value is stored inline, so `from_boxed_carrier` can't be used",
+            )
+        };
+        let mut data = MaybeUninit::new(c);
+        if !is_zst::<T>() {
+            recover_boxed::<T, C>(&mut data);
+        }
+        MaybeBoxIn::from_data(data)
+    }
+}
+
+impl<T: NoUninit> MaybeBox64<T> {
+    /// Consume the `MaybeBox64<T>` and return the `u64` it's stored in. Inline values are stored
+    /// bit-for-bit, with the bytes of the `u64` not covered by the `T` set to zero. Boxed values
+    /// are represented by the address of their heap allocation, whose provenance is exposed so
+    /// that `from_u64` can recover it, as is the provenance of any pointer in an inline value,
+    /// such as a `&U`; see `MaybeBoxIn::into_carrier`.
+    ///
+    /// The `u64` must eventually be turned back into a `MaybeBox64<T>` with `from_u64`. See the
+    /// crate docs on raw words.
+    pub fn into_u64(self) -> u64 {
+        self.into_carrier()
+    }
+
+    /// Reconstruct a `MaybeBox64<T>` from a `u64` returned by `into_u64`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBox64::<T>::into_u64` for the same `T`, and not
+    /// already turned back into a `MaybeBox64<T>`.
+    pub unsafe fn from_u64(raw: u64) -> MaybeBox64<T> {
+        MaybeBoxIn::from_carrier(raw)
+    }
+}
+
+impl<T: NoUninit> MaybeBox32<T> {
+    /// Consume the `MaybeBox32<T>` and return the `u32` it's stored in. This works the same way as
+    /// `MaybeBox64::into_u64`.
+    pub fn into_u32(self) -> u32 {
+        self.into_carrier()
+    }
+
+    /// Reconstruct a `MaybeBox32<T>` from a `u32` returned by `into_u32`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBox32::<T>::into_u32` for the same `T`, and not
+    /// already turned back into a `MaybeBox32<T>`.
+    pub unsafe fn from_u32(raw: u32) -> MaybeBox32<T> {
+        MaybeBoxIn::from_carrier(raw)
+    }
+}
+
+impl<T> MaybeBox64<T> {
+    /// Consume the `MaybeBox64<T>` and return the `u64` it's stored in, for a `T` that's too big
+    /// to be stored inline, such as a `String` or a struct. This is the address of the heap
+    /// allocation, exactly as returned by `into_u64`, but since the `T` is never part of the
+    /// `u64` it doesn't need to implement `NoUninit`.
+    ///
+    /// Fails to compile if a `T` would be stored inline; use `into_u64` for those.
+    ///
+    /// The `u64` must eventually be turned back into a `MaybeBox64<T>` with `from_boxed_u64`. See
+    /// the crate docs on raw words.
+    pub fn into_boxed_u64(self) -> u64 {
+        self.into_boxed_carrier()
+    }
+
+    /// Reconstruct a `MaybeBox64<T>` from a `u64` returned by `into_boxed_u64`, or by `into_u64`
+    /// if `T` implements `NoUninit`. Fails to compile if a `T` would be stored inline.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBox64::<T>::into_boxed_u64` or
+    /// `MaybeBox64::<T>::into_u64` for the same `T`, and not already turned back into a
+    /// `MaybeBox64<T>`.
+    pub unsafe fn from_boxed_u64(raw: u64) -> MaybeBox64<T> {
+        MaybeBoxIn::from_boxed_carrier(raw)
+    }
+}
+
+impl<T> MaybeBox32<T> {
+    /// Consume the `MaybeBox32<T>` and return the `u32` it's stored in, for a `T` that's too big
+    /// to be stored inline. This works the same way as `MaybeBox64::into_boxed_u64`.
+    pub fn into_boxed_u32(self) -> u32 {
+        self.into_boxed_carrier()
+    }
+
+    /// Reconstruct a `MaybeBox32<T>` from a `u32` returned by `into_boxed_u32`, or by `into_u32`
+    /// if `T` implements `NoUninit`. Fails to compile if a `T` would be stored inline.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBox32::<T>::into_boxed_u32` or
+    /// `MaybeBox32::<T>::into_u32` for the same `T`, and not already turned back into a
+    /// `MaybeBox32<T>`.
+    pub unsafe fn from_boxed_u32(raw: u32) -> MaybeBox32<T> {
+        MaybeBoxIn::from_boxed_carrier(raw)
+    }
+}
+
+impl<T: NoUninit> MaybeBox<T> {
+    /// Consume the `MaybeBox<T>` and return the word it's stored in, for passing to C code as a
+    /// `void *`. Inline values are stored bit-for-bit, with the bytes of the word not covered by
+    /// the `T` set to zero. Boxed values are represented by a pointer to their heap allocation.
+    /// Zero-sized values are represented by `align_of::<T>()`.
+    ///
+    /// The word must eventually be turned back into a `MaybeBox<T>` with `from_raw`. See the
+    /// crate docs on raw words.
+    pub fn into_raw(self) -> *mut c_void {
+        let this = ManuallyDrop::new(self);
+        unsafe { read_raw::<T, _>(this.data().as_ptr() as *const *mut c_void) }
+    }
+
+    /// Consume the `MaybeBox<T>` and return the word it's stored in along with a function that
+    /// can be used to drop it. This is for C APIs that take a `(void *data, void (*free)(void *))`
+    /// pair.
+    ///
+    /// The destructor is `MaybeBox::<T>::drop_raw`.
+    pub fn into_raw_with_drop(self) -> (*mut c_void, unsafe extern "C" fn(*mut c_void)) {
+        (self.into_raw(), MaybeBox::<T>::drop_raw)
+    }
+
+    /// Consume the `MaybeBox<T>` and return the word it's stored in as a `usize`. This is the same
+    /// as `into_raw` except that the provenance of the heap pointer of a boxed value, or of an
+    /// inline value that's a pointer such as a `&U` or a `Box<U>`, is exposed so that
+    /// `from_raw_usize` can recover it. The word is the same as the one `into_raw` returns.
+    pub fn into_raw_usize(self) -> usize {
+        self.into_carrier()
+    }
+
+    /// Reconstruct a `MaybeBox<T>` from a word returned by `into_raw_usize`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBox::<T>::into_raw_usize` for the same `T`. The same
+    /// ownership rules as `from_raw` apply.
+    pub unsafe fn from_raw_usize(raw: usize) -> MaybeBox<T> {
+        MaybeBoxIn::from_carrier(raw)
+    }
+}
+
+impl<T> MaybeBox<T> {
+    /// Consume the `MaybeBox<T>` and return the word it's stored in, for a `T` that's too big to
+    /// be stored inline. The word is a pointer to the heap allocation, exactly as returned by
+    /// `into_raw`, but since the `T` is never part of the word it doesn't need to implement
+    /// `NoUninit`. Zero-sized values are represented by `align_of::<T>()`.
+    ///
+    /// Fails to compile if a `T` would be stored inline; use `into_raw` for those.
+    ///
+    /// The word must eventually be turned back into a `MaybeBox<T>` with `from_raw`. See the
+    /// crate docs on raw words.
+    pub fn into_boxed_raw(self) -> *mut c_void {
+        const {
+            assert!(
+                is_zst::<T>() || !fits_inline::<T, usize>(),
+                "value is stored inline, so `into_boxed_raw` can't be used",
+            )
+        };
+        let this = ManuallyDrop::new(self);
+        unsafe { read_boxed_raw(this.data().as_ptr() as *const *mut c_void) }
+    }
+
+    /// The same as `into_raw_with_drop`, for a `T` that's too big to be stored inline. Fails to
+    /// compile if a `T` would be stored inline.
+    pub fn into_boxed_raw_with_drop(self) -> (*mut c_void, unsafe extern "C" fn(*mut c_void)) {
+        (self.into_boxed_raw(), MaybeBox::<T>::drop_raw)
+    }
+
+    /// Reconstruct a `MaybeBox<T>` from a word returned by `into_raw` or `into_boxed_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBox::<T>::into_raw` or
+    /// `MaybeBox::<T>::into_boxed_raw` for the same `T`, and not already turned back into a
+    /// `MaybeBox<T>`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> MaybeBox<T> {
+        MaybeBox::from_data(data_from_raw(raw))
+    }
+
+    /// Drop the value stored in a word returned by `into_raw` or `into_boxed_raw`. This is
+    /// equivalent to passing the word to `from_raw` and dropping the result, but is callable from
+    /// C. If `T`'s destructor panics the process is aborted rather than unwinding into C.
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as `from_raw` apply.
+    pub unsafe extern "C" fn drop_raw(raw: *mut c_void) {
+        drop(MaybeBox::<T>::from_raw(raw));
+    }
+
+    /// Borrow the value stored in a word returned by `into_raw` or `into_boxed_raw` without
+    /// taking ownership of it.
+    ///
+    /// If the value is stored inline then the returned `MaybeBoxRef` dereferences to a copy of it
+    /// made from `raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBox::<T>::into_raw` or
+    /// `MaybeBox::<T>::into_boxed_raw` for the same `T` and not yet passed to `from_raw`. The
+    /// value must not be dropped or mutably borrowed for the lifetime `'a`. If the value is stored
+    /// inline it must not be modified through interior mutability (eg. a `Cell`), since that
+    /// would only modify the copy and leave it out of sync with the word.
+    pub unsafe fn ref_from_raw<'a>(raw: *mut c_void) -> MaybeBoxRef<'a, T> {
+        MaybeBoxRef {
+            inner: ManuallyDrop::new(MaybeBox::from_raw(raw)),
+            _ph: PhantomData,
+        }
+    }
+
+    /// Mutably borrow the value stored in a word returned by `into_raw` or `into_boxed_raw`
+    /// without taking ownership of it.
+    ///
+    /// This takes a reference to the place where the word is stored, rather than the word itself,
+    /// since modifying an inline value means modifying the word. Inline values are always
+    /// `NoUninit`, since they came from `into_raw`, so the word stays initialized.
+    ///
+    /// # Safety
+    ///
+    /// `*raw` must have been returned by `MaybeBox::<T>::into_raw` or
+    /// `MaybeBox::<T>::into_boxed_raw` for the same `T` and not yet passed to `from_raw`. The
+    /// value must not be dropped or borrowed through any other copy of the word for the lifetime
+    /// `'a`.
+    pub unsafe fn mut_from_raw<'a>(raw: &'a mut *mut c_void) -> MaybeBoxMut<'a, T> {
+        MaybeBoxMut {
+            inner: &mut *(raw as *mut *mut c_void as *mut MaybeBox<T>),
+        }
+    }
+}
+
+impl<T, C: Carrier, A: BoxAlloc> MaybeBoxIn<T, C, A> {
+    /// Reconstruct a `MaybeBoxIn<T, C, A>` from storage returned by `into_data`, or by
+    /// `into_data_in` along with the allocator.
+    #[inline]
+    pub(crate) unsafe fn from_data_in(data: MaybeUninit<C>, alloc: A) -> MaybeBoxIn<T, C, A> {
+        MaybeBoxIn {
+            data: ptr::read(&data as *const MaybeUninit<C> as *const C::Storage),
+            alloc: ManuallyDrop::new(alloc),
+            _ph: PhantomData,
+        }
+    }
+
+    /// Take the storage and the allocator out of the `MaybeBoxIn<T, C, A>`. This is `into_data`
+    /// for any allocator.
+    #[inline]
+    pub(crate) fn into_data_in(self) -> (MaybeUninit<C>, A) {
+        let mut this = ManuallyDrop::new(self);
+        unsafe { (ptr::read(this.data()), ManuallyDrop::take(&mut this.alloc)) }
+    }
+
+    /// The storage, which holds either the `T` or a pointer to it.
+    #[inline]
+    fn data(&self) -> &MaybeUninit<C> {
+        unsafe { &*(&self.data as *const C::Storage as *const MaybeUninit<C>) }
+    }
+
+    /// The storage, mutably. If `C::NON_NULL` is set it must never be zeroed.
+    #[inline]
+    fn data_mut(&mut self) -> &mut MaybeUninit<C> {
+        unsafe { &mut *(&mut self.data as *mut C::Storage as *mut MaybeUninit<C>) }
+    }
+
+    /// Consume the `MaybeBoxIn<T, C, A>` and return the inner `T`.
+    pub fn into_inner(self) -> T {
+        let (data, alloc) = self.into_data_in();
+        unsafe {
+            if fits_inline::<T, C>() {
+                ptr::read(data_ptr::<T, C>(&data))
+            } else {
+                let ptr = ptr::read(data.as_ptr() as *const *mut T);
+                let t = ptr::read(ptr);
+                alloc.dealloc(ptr as *mut u8, Layout::new::<T>());
+                t
+            }
         }
     }
 }
 
-impl<T> Drop for MaybeBox<T> {
+impl<T, C: Carrier, A: BoxAlloc> Drop for MaybeBoxIn<T, C, A> {
     fn drop(&mut self) {
-        let _: T = self.get_inner();
+        unsafe {
+            let alloc = ManuallyDrop::take(&mut self.alloc);
+            if fits_inline::<T, C>() {
+                ptr::drop_in_place(data_ptr_mut::<T, C>(self.data_mut()));
+            } else {
+                let ptr = ptr::read(self.data().as_ptr() as *const *mut T);
+                let _guard = DeallocOnDrop {
+                    ptr: ptr as *mut u8,
+                    layout: Layout::new::<T>(),
+                    alloc: &alloc,
+                };
+                ptr::drop_in_place(ptr);
+            }
+        }
     }
 }
 
-impl<T> From<T> for MaybeBox<T> {
-    fn from(t: T) -> MaybeBox<T> {
-        MaybeBox::new(t)
+impl<T, C: Carrier> From<T> for MaybeBoxIn<T, C> {
+    fn from(t: T) -> MaybeBoxIn<T, C> {
+        MaybeBoxIn::new(t)
     }
 }
 
-impl<T> Deref for MaybeBox<T> {
+impl<T, C: Carrier, A: BoxAlloc> Deref for MaybeBoxIn<T, C, A> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        let ptr = &self.data;
-        if mem::size_of::<T>() <= mem::size_of::<usize>() {
-            unsafe { transmogrify_inline::<T>(ptr) }
-        } else {
-            &*unsafe { transmogrify_boxed::<T>(ptr) }
-        }
+        unsafe { &*data_ptr(self.data()) }
     }
 }
 
-impl<T> DerefMut for MaybeBox<T> {
+impl<T, C: Carrier, A: BoxAlloc> DerefMut for MaybeBoxIn<T, C, A> {
     fn deref_mut(&mut self) -> &mut T {
-        let ptr = &mut self.data;
-        if mem::size_of::<T>() <= mem::size_of::<usize>() {
-            unsafe { transmogrify_inline_mut::<T>(ptr) }
-        } else {
-            &mut *unsafe { transmogrify_boxed_mut::<T>(ptr) }
-        }
+        unsafe { &mut *data_ptr_mut(self.data_mut()) }
     }
 }
 
-impl<T: fmt::Debug> fmt::Debug for MaybeBox<T> {
+impl<T: fmt::Debug, C: Carrier, A: BoxAlloc> fmt::Debug for MaybeBoxIn<T, C, A> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &T = &**self;
-        f.debug_tuple("MaybeBox").field(inner).finish()
+        let inner: &T = self;
+        f.debug_tuple(C::DEBUG_NAME).field(inner).finish()
     }
 }
 
-impl<U, T: PartialEq<U>> PartialEq<MaybeBox<U>> for MaybeBox<T> {
-    fn eq(&self, other: &MaybeBox<U>) -> bool {
-        let l: &T = &**self;
-        let r: &U = &**other;
+impl<U, T, C, D, A, B> PartialEq<MaybeBoxIn<U, D, B>> for MaybeBoxIn<T, C, A>
+    where T: PartialEq<U>,
+          C: Carrier,
+          D: Carrier,
+          A: BoxAlloc,
+          B: BoxAlloc
+{
+    fn eq(&self, other: &MaybeBoxIn<U, D, B>) -> bool {
+        let l: &T = self;
+        let r: &U = other;
         *l == *r
     }
-
-    fn ne(&self, other: &MaybeBox<U>) -> bool {
-        let l: &T = &**self;
-        let r: &U = &**other;
-        *l != *r
-    }
 }
 
-impl<T: Eq> Eq for MaybeBox<T> {}
+impl<T: Eq, C: Carrier, A: BoxAlloc> Eq for MaybeBoxIn<T, C, A> {}
 
-impl<T: hash::Hash> hash::Hash for MaybeBox<T> {
+impl<T: hash::Hash, C: Carrier, A: BoxAlloc> hash::Hash for MaybeBoxIn<T, C, A> {
     fn hash<H>(&self, state: &mut H)
         where H: hash::Hasher
     {
-        let inner: &T = &**self;
+        let inner: &T = self;
         T::hash(inner, state)
     }
 }
 
+/// A borrowed `MaybeBox<T>`, produced by `MaybeBox::ref_from_raw`.
+pub struct MaybeBoxRef<'a, T: 'a> {
+    inner: ManuallyDrop<MaybeBox<T>>,
+    _ph: PhantomData<&'a T>,
+}
+
+impl<'a, T> Deref for MaybeBoxRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for MaybeBoxRef<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner: &T = self;
+        f.debug_tuple("MaybeBoxRef").field(inner).finish()
+    }
+}
+
+/// A mutably borrowed `MaybeBox<T>`, produced by `MaybeBox::mut_from_raw`.
+pub struct MaybeBoxMut<'a, T: 'a> {
+    inner: &'a mut MaybeBox<T>,
+}
+
+impl<'a, T> Deref for MaybeBoxMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner
+    }
+}
+
+impl<'a, T> DerefMut for MaybeBoxMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for MaybeBoxMut<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner: &T = self;
+        f.debug_tuple("MaybeBoxMut").field(inner).finish()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -248,5 +966,520 @@ mod test {
             x => panic!("Unexpected!: {:?}", x),
         };
     }
-}
 
+    #[derive(Debug, PartialEq)]
+    #[repr(align(16))]
+    struct OverAligned(u8);
+
+    #[test]
+    fn over_aligned() {
+        let mb = MaybeBox::new(OverAligned(123));
+        assert_eq!(&*mb as *const OverAligned as usize % 16, 0);
+        assert_eq!(mb.0, 123);
+        match mb.unpack() {
+            Unpacked::Boxed(b) => assert_eq!(*b, OverAligned(123)),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+
+        let mut mb = MaybeBox::new(OverAligned(1));
+        mb.0 = 2;
+        assert_eq!(&*mb as *const OverAligned as usize % 16, 0);
+        assert_eq!(mb.into_inner(), OverAligned(2));
+
+        #[derive(Debug, PartialEq)]
+        #[repr(align(8))]
+        struct Align8(u8);
+
+        let mb = MaybeBox::new(Align8(7));
+        assert_eq!(&*mb as *const Align8 as usize % 8, 0);
+        if std::mem::align_of::<usize>() >= 8 {
+            match mb.unpack() {
+                Unpacked::Inline(a) => assert_eq!(a, Align8(7)),
+                x => panic!("Unexpected!: {:?}", x),
+            };
+        }
+    }
+
+    #[test]
+    fn niche_types() {
+        use std::cmp::Ordering;
+        use std::num::NonZeroU8;
+
+        let x = 5u32;
+        let mb = MaybeBox::new(&x);
+        assert_eq!(**mb, 5);
+        assert_eq!(*mb.into_inner(), 5);
+
+        let mut mb = MaybeBox::new(Ordering::Less);
+        *mb = Ordering::Greater;
+        assert_eq!(mb.into_inner(), Ordering::Greater);
+
+        let mb = MaybeBox::new(NonZeroU8::new(3));
+        assert_eq!(*mb, NonZeroU8::new(3));
+        match mb.unpack() {
+            Unpacked::Inline(n) => assert_eq!(n, NonZeroU8::new(3)),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+
+        let mb = MaybeBox::new(Some(String::from("hello")));
+        assert_eq!(mb.as_ref().map(|s| &s[..]), Some("hello"));
+        drop(mb);
+    }
+
+    fn hash_of<T: std::hash::Hash>(t: &T) -> u64 {
+        use std::hash::Hasher;
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        t.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn exercise<T>(a: T, b: T, boxed: bool)
+        where T: Clone + fmt::Debug + PartialEq + std::hash::Hash
+    {
+        let mut mb = MaybeBox::new(a.clone());
+        assert_eq!(*mb, a);
+        assert_eq!(format!("{:?}", mb), format!("MaybeBox({:?})", a));
+        assert_eq!(hash_of(&mb), hash_of(&a));
+        assert!(mb == MaybeBox::from(a.clone()));
+        assert!(mb != MaybeBox::from(b.clone()));
+
+        *mb = b.clone();
+        assert_eq!(*mb, b);
+        assert_eq!(mb.into_inner(), b);
+
+        match (MaybeBox::new(a.clone()).unpack(), boxed) {
+            (Unpacked::Inline(t), false) => assert_eq!(t, a),
+            (Unpacked::Boxed(t), true) => assert_eq!(*t, a),
+            (x, _) => panic!("Unexpected!: {:?}", x),
+        };
+
+        drop(MaybeBox::new(b));
+    }
+
+    #[test]
+    fn all_methods() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MaybeBox<u8>>();
+        assert_send_sync::<MaybeBox<String>>();
+
+        let x = 1u32;
+        let y = 2u32;
+        exercise(1u8, 2u8, false);
+        exercise(1usize, 2usize, false);
+        exercise(&x, &y, false);
+        exercise(Box::new(1u32), Box::new(2u32), false);
+        exercise(String::from("hello"), String::from("world"), true);
+        exercise([1u64; 4], [2u64; 4], true);
+        exercise(vec![String::from("a")], vec![], true);
+    }
+
+    #[test]
+    fn zero_sized() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Debug, PartialEq)]
+        struct Empty;
+
+        #[derive(Debug)]
+        #[repr(align(64))]
+        struct AlignedEmpty;
+
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Droppable;
+
+        impl Drop for Droppable {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let mb = MaybeBox::new(());
+        assert_eq!(*mb, ());
+        match mb.unpack() {
+            Unpacked::Inline(()) => (),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+
+        let mb = MaybeBox::new(Empty);
+        assert_eq!(format!("{:?}", mb), "MaybeBox(Empty)");
+        assert_eq!(mb.into_inner(), Empty);
+
+        let mb = MaybeBox::new(AlignedEmpty);
+        assert_eq!(&*mb as *const AlignedEmpty as usize, 64);
+        match mb.unpack() {
+            Unpacked::Inline(AlignedEmpty) => (),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+
+        let mb = MaybeBox::new(|x: u32| x + 1);
+        assert_eq!((*mb)(1), 2);
+        assert_eq!((*mb)(2), 3);
+        let f = mb.into_inner();
+        assert_eq!(f(3), 4);
+
+        // Zero-sized types with side-effecting destructors are dropped exactly once.
+        {
+            let _mb = MaybeBox::new(Droppable);
+        }
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+        let d = MaybeBox::new(Droppable).into_inner();
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+        drop(d);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+        match MaybeBox::new(Droppable).unpack() {
+            Unpacked::Inline(_) => (),
+            Unpacked::Boxed(_) => panic!("Unexpected!"),
+        };
+        assert_eq!(DROPS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        #[derive(Debug, PartialEq)]
+        #[repr(align(64))]
+        struct AlignedEmpty;
+
+        unsafe impl NoUninit for AlignedEmpty {}
+
+        let raw = MaybeBox::new(0x1234u16).into_raw();
+        let bytes = (raw as usize).to_ne_bytes();
+        assert_eq!(&bytes[..2], &0x1234u16.to_ne_bytes()[..]);
+        assert!(bytes[2..].iter().all(|b| *b == 0));
+        assert_eq!(unsafe { MaybeBox::<u16>::from_raw(raw) }.into_inner(), 0x1234);
+
+        let raw = MaybeBox::new(123usize).into_raw();
+        assert_eq!(raw as usize, 123);
+        assert_eq!(unsafe { MaybeBox::<usize>::from_raw(raw) }.into_inner(), 123);
+
+        let raw = MaybeBox::new(true).into_raw();
+        assert!(*unsafe { MaybeBox::<bool>::from_raw(raw) });
+
+        let x = 5u32;
+        let raw = MaybeBox::new(&x).into_raw();
+        assert_eq!(raw as *const u32, &x as *const u32);
+        assert_eq!(**unsafe { MaybeBox::<&u32>::from_raw(raw) }, 5);
+
+        let mb = MaybeBox::new([1u64, 2, 3, 4]);
+        let heap = &*mb as *const [u64; 4];
+        let raw = mb.into_raw();
+        assert_eq!(raw as *const [u64; 4], heap);
+        let mb = unsafe { MaybeBox::<[u64; 4]>::from_raw(raw) };
+        assert_eq!(mb.into_inner(), [1, 2, 3, 4]);
+
+        let raw = MaybeBox::new(()).into_raw();
+        assert_eq!(raw as usize, 1);
+        unsafe { MaybeBox::<()>::from_raw(raw) }.into_inner();
+
+        let raw = MaybeBox::new(AlignedEmpty).into_raw();
+        assert_eq!(raw as usize, 64);
+        assert_eq!(unsafe { MaybeBox::<AlignedEmpty>::from_raw(raw) }.into_inner(), AlignedEmpty);
+    }
+
+    #[test]
+    fn borrow_raw() {
+        let raw = MaybeBox::new(123u32).into_raw();
+        {
+            let r = unsafe { MaybeBox::<u32>::ref_from_raw(raw) };
+            assert_eq!(*r, 123);
+            assert_eq!(format!("{:?}", r), "MaybeBoxRef(123)");
+        }
+        let mut raw = raw;
+        {
+            let mut m = unsafe { MaybeBox::<u32>::mut_from_raw(&mut raw) };
+            *m += 1;
+            assert_eq!(format!("{:?}", m), "MaybeBoxMut(124)");
+        }
+        assert_eq!(*unsafe { MaybeBox::<u32>::ref_from_raw(raw) }, 124);
+        assert_eq!(unsafe { MaybeBox::<u32>::from_raw(raw) }.into_inner(), 124);
+
+        let raw = MaybeBox::new(String::from("hello")).into_boxed_raw();
+        for _ in 0..3 {
+            let r = unsafe { MaybeBox::<String>::ref_from_raw(raw) };
+            assert_eq!(&**r, "hello");
+        }
+        let mut copy = raw;
+        unsafe { MaybeBox::<String>::mut_from_raw(&mut copy) }.push_str(" world");
+        assert_eq!(copy, raw);
+        assert_eq!(&**unsafe { MaybeBox::<String>::ref_from_raw(raw) }, "hello world");
+        assert_eq!(unsafe { MaybeBox::<String>::from_raw(raw) }.into_inner(), "hello world");
+    }
+
+    #[test]
+    fn raw_with_drop() {
+        use std::rc::Rc;
+
+        let rc = Rc::new(());
+        let (raw, free) = MaybeBox::new(rc.clone()).into_raw_with_drop();
+        assert_eq!(Rc::strong_count(&rc), 2);
+        unsafe { free(raw) };
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let (raw, free) = MaybeBox::new([rc.clone(), rc.clone()]).into_raw_with_drop();
+        assert_eq!(Rc::strong_count(&rc), 3);
+        assert_eq!(Rc::strong_count(&unsafe { MaybeBox::<[Rc<()>; 2]>::ref_from_raw(raw) }[0]), 3);
+        unsafe { free(raw) };
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        // An ordinary struct, with padding and a `String`, which isn't `NoUninit`.
+        struct Userdata {
+            name: String,
+            id: u8,
+            _rc: Rc<()>,
+        }
+
+        let userdata = Userdata { name: String::from("conn"), id: 7, _rc: rc.clone() };
+        let (raw, free) = MaybeBox::new(userdata).into_boxed_raw_with_drop();
+        let userdata = unsafe { MaybeBox::<Userdata>::ref_from_raw(raw) };
+        assert_eq!((&userdata.name[..], userdata.id), ("conn", 7));
+        assert_eq!(Rc::strong_count(&rc), 2);
+        unsafe { free(raw) };
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let (raw, free) = MaybeBox::new(String::from("hello")).into_boxed_raw_with_drop();
+        assert_eq!(unsafe { MaybeBox::<String>::ref_from_raw(raw) }.len(), 5);
+        unsafe { free(raw) };
+    }
+
+    #[test]
+    fn uninit_bytes() {
+        use std::num::NonZeroU16;
+
+        // Types with padding or unused bytes have to be boxed to go through a raw word.
+        let raw = MaybeBox::new(Box::new((1u8, 2u16))).into_raw();
+        let mut raw = raw;
+        unsafe { MaybeBox::<Box<(u8, u16)>>::mut_from_raw(&mut raw) }.1 = 3;
+        assert_eq!(*unsafe { MaybeBox::<Box<(u8, u16)>>::from_raw(raw) }.into_inner(), (1, 3));
+
+        // Types that are always boxed don't need to be `NoUninit`, since their bytes are never
+        // part of the word.
+        struct Empty;
+        let raw = MaybeBox::new((1u8, [2u64; 2])).into_boxed_raw();
+        assert_eq!(unsafe { MaybeBox::<(u8, [u64; 2])>::from_raw(raw) }.into_inner(), (1, [2; 2]));
+        let raw = MaybeBox::new(Empty).into_boxed_raw();
+        assert_eq!(raw as usize, 1);
+        unsafe { MaybeBox::<Empty>::drop_raw(raw) };
+
+        let (raw, free) = MaybeBox::new(Box::new(None::<u16>)).into_raw_with_drop();
+        assert_eq!(**unsafe { MaybeBox::<Box<Option<u16>>>::ref_from_raw(raw) }, None);
+        unsafe { free(raw) };
+
+        // Options with a niche are fully initialized, with `None` as zeroes.
+        let raw = MaybeBox::new(None::<NonZeroU16>).into_raw();
+        assert!(raw.is_null());
+        let raw = MaybeBox::new(NonZeroU16::new(5)).into_raw();
+        assert_eq!(unsafe { MaybeBox::<Option<NonZeroU16>>::from_raw(raw) }.into_inner(),
+                   NonZeroU16::new(5));
+    }
+
+    #[test]
+    fn multi_word() {
+        assert_eq!(std::mem::size_of::<[usize; 2]>(),
+                   std::mem::size_of::<MaybeBoxN<(u64, u64), 2>>());
+        assert_eq!(std::mem::size_of::<[usize; 4]>(),
+                   std::mem::size_of::<MaybeBoxN<String, 4>>());
+
+        let mut mb = MaybeBoxN::<_, 2>::new((1usize, 2usize));
+        mb.1 = 3;
+        assert_eq!(*mb, (1, 3));
+        match mb.unpack() {
+            Unpacked::Inline(t) => assert_eq!(t, (1, 3)),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+
+        let s: &str = "hello";
+        let mb = MaybeBoxN::<&str, 2>::new(s);
+        assert_eq!(format!("{:?}", mb), "MaybeBoxN(\"hello\")");
+        assert!(mb == MaybeBoxN::<&str, 2>::from("hello"));
+        match mb.unpack() {
+            Unpacked::Inline(t) => assert_eq!(t, "hello"),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+
+        let mb = MaybeBoxN::<[usize; 3], 2>::new([1, 2, 3]);
+        assert_eq!(mb[2], 3);
+        match mb.unpack() {
+            Unpacked::Boxed(t) => assert_eq!(*t, [1, 2, 3]),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+
+        let mb = MaybeBoxN::<_, 3>::new(String::from("hello"));
+        assert_eq!(mb.into_inner(), "hello");
+        let mb = MaybeBoxN::<_, 2>::new(());
+        assert_eq!(mb.into_inner(), ());
+
+        assert_eq!(std::mem::size_of::<usize>(),
+                   std::mem::size_of::<MaybeBoxN<String, 1>>());
+        let mb = MaybeBoxN::<u8, 1>::new(5);
+        assert!(mb == MaybeBox::new(5));
+        assert_eq!(mb.into_inner(), 5);
+    }
+
+    #[test]
+    fn fixed_width() {
+        assert_eq!(std::mem::size_of::<MaybeBox64<u8>>(), 8);
+        assert_eq!(std::mem::size_of::<MaybeBox32<u8>>(), 4);
+
+        // Values of up to 8 bytes are always inline in a MaybeBox64, whatever the pointer width.
+        let mb = MaybeBox64::new((1u32, 2u32));
+        assert_eq!(*mb, (1, 2));
+        assert_eq!(format!("{:?}", mb), "MaybeBox64((1, 2))");
+        match mb.unpack() {
+            Unpacked::Inline(t) => assert_eq!(t, (1, 2)),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+
+        let raw = MaybeBox64::new(0x0102_0304_0506_0708u64).into_u64();
+        assert_eq!(raw, 0x0102_0304_0506_0708);
+        assert_eq!(unsafe { MaybeBox64::<u64>::from_u64(raw) }.into_inner(), raw);
+
+        let raw = MaybeBox64::new(7u8).into_u64();
+        assert_eq!(raw.to_ne_bytes(), [7, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(unsafe { MaybeBox64::<u8>::from_u64(raw) }.into_inner(), 7);
+
+        let mut mb = MaybeBox32::new([1u8, 2, 3, 4]);
+        mb[3] = 5;
+        assert_eq!(format!("{:?}", mb), "MaybeBox32([1, 2, 3, 5])");
+        let raw = mb.into_u32();
+        assert_eq!(raw.to_ne_bytes(), [1, 2, 3, 5]);
+        assert_eq!(unsafe { MaybeBox32::<[u8; 4]>::from_u32(raw) }.into_inner(), [1, 2, 3, 5]);
+
+        let mb = MaybeBox32::new(());
+        assert_eq!(mb.into_inner(), ());
+    }
+
+    #[test]
+    fn carriers() {
+        let x = 5u32;
+        let p = MaybeBoxIn::<&u32, *mut c_void>::new(&x).into_carrier();
+        assert_eq!(p as *const u32, &x as *const u32);
+        assert_eq!(**unsafe { MaybeBoxIn::<&u32, *mut c_void>::from_carrier(p) }, 5);
+
+        let p = MaybeBoxIn::<_, *mut c_void>::new([1u64, 2, 3, 4]).into_carrier();
+        let mb = unsafe { MaybeBoxIn::<[u64; 4], *mut c_void>::from_carrier(p) };
+        assert_eq!(&*mb as *const [u64; 4] as *mut c_void, p);
+        assert_eq!(mb.into_inner(), [1, 2, 3, 4]);
+
+        // A NonNull carrier boxes everything that isn't zero-sized, even zeroes, so it's never null.
+        assert_eq!(std::mem::size_of::<Option<MaybeBoxIn<u8, NonNull<()>>>>(),
+                   std::mem::size_of::<usize>());
+        let p = MaybeBoxIn::<u8, NonNull<()>>::new(0).into_carrier();
+        let mut mb = unsafe { MaybeBoxIn::<u8, NonNull<()>>::from_carrier(p) };
+        *mb += 1;
+        match mb.unpack() {
+            Unpacked::Boxed(b) => assert_eq!(*b, 1),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+        let p = MaybeBoxIn::<(), NonNull<()>>::new(()).into_carrier();
+        assert_eq!(p, NonNull::dangling());
+        match unsafe { MaybeBoxIn::<(), NonNull<()>>::from_carrier(p) }.unpack() {
+            Unpacked::Inline(()) => (),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+        let p = MaybeBoxIn::<_, NonNull<()>>::new(String::from("hello")).into_boxed_carrier();
+        let mb = unsafe { MaybeBoxIn::<String, NonNull<()>>::from_boxed_carrier(p) };
+        assert_eq!(format!("{:?}", mb), "MaybeBoxIn(\"hello\")");
+
+        let mb = MaybeBoxIn::<i16, isize>::new(-2);
+        assert_eq!(format!("{:?}", mb), "MaybeBoxIn(-2)");
+        let raw = mb.into_carrier();
+        assert_eq!(unsafe { MaybeBoxIn::<i16, isize>::from_carrier(raw) }.into_inner(), -2);
+        let raw = MaybeBoxN::<[u64; 2], 2>::new([1, 2]).into_carrier();
+        assert_eq!(raw, [1, 2]);
+        assert_eq!(unsafe { MaybeBoxN::<[u64; 2], 2>::from_carrier(raw) }.into_inner(), [1, 2]);
+    }
+
+    /// Tests of recovering boxed values from integers, which relies on exposed provenance. Strict
+    /// provenance forbids that, so under Miri these need `-Zmiri-permissive-provenance`.
+    mod exposed_provenance {
+        use super::*;
+
+        #[test]
+        fn raw_usize_round_trip() {
+            let raw = MaybeBox::new(123u32).into_raw_usize();
+            assert_eq!(unsafe { MaybeBox::<u32>::from_raw_usize(raw) }.into_inner(), 123);
+
+            let raw = MaybeBox::new([1u64, 2, 3, 4]).into_raw_usize();
+            let mb = unsafe { MaybeBox::<[u64; 4]>::from_raw_usize(raw) };
+            assert_eq!(mb.into_inner(), [1, 2, 3, 4]);
+
+            let raw = MaybeBox::new(()).into_raw_usize();
+            assert_eq!(raw, 1);
+            unsafe { MaybeBox::<()>::from_raw_usize(raw) }.into_inner();
+        }
+
+        #[test]
+        fn raw_usize_pointers() {
+            // Inline values holding pointers stay inline, with their provenance exposed.
+            let x = 5u32;
+            let raw = MaybeBox::new(&x).into_raw_usize();
+            assert_eq!(raw, &x as *const u32 as usize);
+            let mb = unsafe { MaybeBox::<&u32>::from_raw_usize(raw) };
+            assert_eq!(**mb, 5);
+            match mb.unpack() {
+                Unpacked::Inline(r) => assert_eq!(r as *const u32, &x as *const u32),
+                x => panic!("Unexpected!: {:?}", x),
+            };
+
+            let raw = MaybeBox::new(Box::new(String::from("hello"))).into_raw_usize();
+            let mb = unsafe { MaybeBox::<Box<String>>::from_raw_usize(raw) };
+            assert_eq!(&**mb, "hello");
+            assert_eq!(*mb.into_inner(), "hello");
+
+            let raw = MaybeBox::new(Some(&x)).into_raw_usize();
+            assert_eq!(unsafe { MaybeBox::<Option<&u32>>::from_raw_usize(raw) }.into_inner(),
+                       Some(&5));
+
+            let raw = MaybeBoxN::<[&u32; 2], 2>::new([&x, &x]).into_carrier();
+            let mb = unsafe { MaybeBoxN::<[&u32; 2], 2>::from_carrier(raw) };
+            assert_eq!(*mb[0] + *mb[1], 10);
+
+            // Values without pointers stay inline.
+            let raw = MaybeBox::new([1u8, 2]).into_raw_usize();
+            assert_eq!(raw.to_ne_bytes()[..2], [1, 2]);
+            assert_eq!(unsafe { MaybeBox::<[u8; 2]>::from_raw_usize(raw) }.into_inner(), [1, 2]);
+        }
+
+        #[test]
+        fn fixed_width_pointers() {
+            let x = 5u32;
+            let raw = MaybeBox64::new(&x).into_u64();
+            assert_eq!(raw, &x as *const u32 as usize as u64);
+            let mb = unsafe { MaybeBox64::<&u32>::from_u64(raw) };
+            assert_eq!(**mb, 5);
+
+            let raw = MaybeBox64::new(Box::new(7u8)).into_u64();
+            assert_eq!(*unsafe { MaybeBox64::<Box<u8>>::from_u64(raw) }.into_inner(), 7);
+
+            // Boxed values don't need to be `NoUninit`.
+            struct Conn {
+                name: String,
+                id: u8,
+            }
+
+            let conn = Conn { name: String::from("conn"), id: 3 };
+            let raw = MaybeBox64::new(conn).into_boxed_u64();
+            let conn = unsafe { MaybeBox64::<Conn>::from_boxed_u64(raw) }.into_inner();
+            assert_eq!((&conn.name[..], conn.id), ("conn", 3));
+
+            let raw = MaybeBoxN::<_, 2>::new(String::from("hello")).into_boxed_carrier();
+            let mb = unsafe { MaybeBoxN::<String, 2>::from_boxed_carrier(raw) };
+            assert_eq!(mb.into_inner(), "hello");
+
+            let raw = MaybeBox64::new([1u64, 2, 3]).into_boxed_u64();
+            assert_eq!(unsafe { MaybeBox64::<[u64; 3]>::from_u64(raw) }.into_inner(), [1, 2, 3]);
+        }
+
+        #[test]
+        fn fixed_width_boxed() {
+            let raw = MaybeBox64::new([1u64, 2, 3]).into_u64();
+            let mb = unsafe { MaybeBox64::<[u64; 3]>::from_u64(raw) };
+            assert_eq!(mb[2], 3);
+            match mb.unpack() {
+                Unpacked::Boxed(b) => assert_eq!(*b, [1, 2, 3]),
+                x => panic!("Unexpected!: {:?}", x),
+            };
+        }
+    }
+}
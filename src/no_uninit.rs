@@ -0,0 +1,91 @@
+use std::marker::PhantomData;
+use std::num;
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Types whose values never contain uninitialized bytes, so that they can be stored inline in a
+/// word that's handed to C.
+///
+/// Methods such as `MaybeBox::into_raw` return the word a value is stored in as a `void *` or an
+/// integer. If the value is stored inline then its bytes are read as part of that word, which is
+/// undefined behaviour if any of them are uninitialized. So these methods require `T: NoUninit`.
+///
+/// Types that are too big to be stored inline are only ever represented by a pointer, so they
+/// don't need to implement this trait if they're exported with a method such as
+/// `MaybeBox::into_boxed_raw` instead. That fails to compile for types that would be stored
+/// inline. Those can be wrapped in a `Box`, which implements this trait.
+///
+/// ```compile_fail
+/// # use maybe_box::MaybeBox;
+/// let raw = MaybeBox::new((1u8, 2u16)).into_raw();
+/// ```
+///
+/// ```compile_fail
+/// # use maybe_box::MaybeBox;
+/// let raw = MaybeBox::new((1u8, 2u16)).into_boxed_raw();
+/// ```
+///
+/// ```
+/// # use maybe_box::MaybeBox;
+/// let raw = MaybeBox::new(Box::new((1u8, 2u16))).into_raw();
+/// # drop(unsafe { MaybeBox::<Box<(u8, u16)>>::from_raw(raw) });
+/// let raw = MaybeBox::new(String::from("hello")).into_boxed_raw();
+/// # drop(unsafe { MaybeBox::<String>::from_raw(raw) });
+/// ```
+///
+/// Some methods, such as `MaybeBox::into_raw_usize`, return the word as an integer, which can't
+/// hold a pointer's provenance. The provenance of any pointer in an inline value that might hold
+/// one, such as a `&T` or a `Box<T>`, is exposed on the way into such an integer and recovered on
+/// the way back. Types that never hold pointers set `HOLDS_POINTERS` to `false` to skip that.
+///
+/// # Safety
+///
+/// No value of the type may contain uninitialized bytes. This rules out types with padding, such
+/// as `(u8, u16)`, and enums with variants that leave some bytes unused, such as `Option<u16>`.
+///
+/// `HOLDS_POINTERS` may only be `false` if no value of the type contains a pointer.
+pub unsafe trait NoUninit {
+    /// Whether values of the type might contain a pointer, whose provenance would be lost if the
+    /// value were read out as an integer. This is `true` unless overridden.
+    const HOLDS_POINTERS: bool = true;
+}
+
+/// Implement `NoUninit` for types that never hold pointers.
+macro_rules! impl_pointer_free {
+    ($($ty:ty),* $(,)*) => {
+        $(unsafe impl NoUninit for $ty { const HOLDS_POINTERS: bool = false; })*
+    };
+}
+
+impl_pointer_free!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char, (),
+);
+impl_pointer_free!(
+    num::NonZeroU8, num::NonZeroU16, num::NonZeroU32, num::NonZeroU64, num::NonZeroUsize,
+    num::NonZeroI8, num::NonZeroI16, num::NonZeroI32, num::NonZeroI64, num::NonZeroIsize,
+);
+unsafe impl<T: ?Sized> NoUninit for *const T {}
+unsafe impl<T: ?Sized> NoUninit for *mut T {}
+unsafe impl<T: ?Sized> NoUninit for &T {}
+unsafe impl<T: ?Sized> NoUninit for &mut T {}
+unsafe impl<T: ?Sized> NoUninit for NonNull<T> {}
+unsafe impl<T: ?Sized> NoUninit for Box<T> {}
+unsafe impl<T: ?Sized> NoUninit for Rc<T> {}
+unsafe impl<T: ?Sized> NoUninit for Arc<T> {}
+unsafe impl<T: ?Sized> NoUninit for PhantomData<T> { const HOLDS_POINTERS: bool = false; }
+unsafe impl<T: NoUninit, const N: usize> NoUninit for [T; N] {
+    const HOLDS_POINTERS: bool = T::HOLDS_POINTERS;
+}
+
+// `None` is guaranteed to be represented by zeroes for these types, so every byte is initialized.
+unsafe impl<T> NoUninit for Option<&T> {}
+unsafe impl<T> NoUninit for Option<&mut T> {}
+unsafe impl<T> NoUninit for Option<NonNull<T>> {}
+unsafe impl<T> NoUninit for Option<Box<T>> {}
+impl_pointer_free!(
+    Option<num::NonZeroU8>, Option<num::NonZeroU16>, Option<num::NonZeroU32>,
+    Option<num::NonZeroU64>, Option<num::NonZeroUsize>, Option<num::NonZeroI8>,
+    Option<num::NonZeroI16>, Option<num::NonZeroI32>, Option<num::NonZeroI64>,
+    Option<num::NonZeroIsize>,
+);
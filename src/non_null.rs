@@ -0,0 +1,219 @@
+use std::fmt;
+use std::hash;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::num;
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use {Unpacked, write_data, data_ptr, data_ptr_mut, read_data};
+
+/// Types whose values are never represented by all-zero bytes, so that they can be stored inline
+/// in a `NonNullMaybeBox`.
+///
+/// # Safety
+///
+/// If the type is no bigger than a `usize` and no more aligned, then it must not have any padding
+/// bytes and no value of the type may have all of its bytes set to zero. Types that are bigger or
+/// more aligned than a `usize` are always boxed by `NonNullMaybeBox`, so any such type can
+/// implement this trait.
+pub unsafe trait NeverZero {}
+
+unsafe impl NeverZero for num::NonZeroU8 {}
+unsafe impl NeverZero for num::NonZeroU16 {}
+unsafe impl NeverZero for num::NonZeroU32 {}
+unsafe impl NeverZero for num::NonZeroU64 {}
+unsafe impl NeverZero for num::NonZeroUsize {}
+unsafe impl NeverZero for num::NonZeroI8 {}
+unsafe impl NeverZero for num::NonZeroI16 {}
+unsafe impl NeverZero for num::NonZeroI32 {}
+unsafe impl NeverZero for num::NonZeroI64 {}
+unsafe impl NeverZero for num::NonZeroIsize {}
+unsafe impl<T: ?Sized> NeverZero for &T {}
+unsafe impl<T: ?Sized> NeverZero for &mut T {}
+unsafe impl<T: ?Sized> NeverZero for NonNull<T> {}
+unsafe impl<T: ?Sized> NeverZero for Box<T> {}
+unsafe impl<T: ?Sized> NeverZero for Rc<T> {}
+unsafe impl<T: ?Sized> NeverZero for Arc<T> {}
+unsafe impl NeverZero for () {}
+unsafe impl<T: ?Sized> NeverZero for PhantomData<T> {}
+
+/// Hold a value of type `T` in the space for a non-null pointer, only boxing it if necessary.
+///
+/// Unlike `MaybeBox<T>`, the word is never zero, so `Option<NonNullMaybeBox<T>>` is the same size
+/// as a `usize` too. This is useful for storing an optional `void *` userdata. It requires
+/// `T: NeverZero` though, since an inline `T` that was all zeroes would make the word zero.
+///
+/// For types that can be zero, such as integers or `bool`, use `NonNullMaybeBox<Box<T>>`, which
+/// always allocates (unless `T` is zero-sized), or `Option<MaybeBox<T>>`, which is two words.
+#[repr(transparent)]
+pub struct NonNullMaybeBox<T: NeverZero> {
+    data: NonNull<()>,
+    _ph: PhantomData<T>,
+}
+
+unsafe impl<T: NeverZero + Send> Send for NonNullMaybeBox<T> {}
+unsafe impl<T: NeverZero + Sync> Sync for NonNullMaybeBox<T> {}
+
+const _: () = {
+    assert!(mem::size_of::<Option<NonNullMaybeBox<()>>>() == mem::size_of::<usize>());
+    assert!(mem::size_of::<Option<NonNullMaybeBox<num::NonZeroU32>>>() == mem::size_of::<usize>());
+    assert!(mem::size_of::<Option<NonNullMaybeBox<&str>>>() == mem::size_of::<usize>());
+    assert!(mem::size_of::<Option<NonNullMaybeBox<Box<u8>>>>() == mem::size_of::<usize>());
+    assert!(mem::size_of::<Option<NonNullMaybeBox<Arc<String>>>>() == mem::size_of::<usize>());
+};
+
+impl<T: NeverZero> NonNullMaybeBox<T> {
+    /// Wrap a `T` into a `NonNullMaybeBox<T>`. This will allocate if `T` is bigger or more aligned
+    /// than a `usize`. Zero-sized types never allocate.
+    #[inline]
+    pub fn new(t: T) -> NonNullMaybeBox<T> {
+        // This is the same representation as a `MaybeBox<T>`, which `T: NeverZero` guarantees
+        // isn't zero.
+        let mut data = MaybeUninit::<usize>::zeroed();
+        unsafe {
+            write_data(t, &mut data);
+            NonNullMaybeBox {
+                data: mem::transmute::<MaybeUninit<usize>, NonNull<()>>(data),
+                _ph: PhantomData,
+            }
+        }
+    }
+
+    /// Consume the `NonNullMaybeBox<T>` and return the inner `T`.
+    pub fn into_inner(self) -> T {
+        match self.unpack() {
+            Unpacked::Inline(t) => t,
+            Unpacked::Boxed(b) => *b,
+        }
+    }
+
+    /// Consume the `NonNullMaybeBox<T>` and return the inner `T`, possibly boxed (if it was
+    /// already).
+    pub fn unpack(self) -> Unpacked<T> {
+        let mut this = ManuallyDrop::new(self);
+        unsafe { read_data(this.word_mut()) }
+    }
+
+    /// Consume the `NonNullMaybeBox<T>` and return the word it's stored in. This is represented the
+    /// same way as the word returned by `MaybeBox::<T>::into_raw`.
+    ///
+    /// To avoid a leak the word must eventually be turned back into a `NonNullMaybeBox<T>` with
+    /// `from_raw`.
+    pub fn into_raw(self) -> NonNull<c_void> {
+        ManuallyDrop::new(self).data.cast()
+    }
+
+    /// Reconstruct a `NonNullMaybeBox<T>` from a word returned by `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `NonNullMaybeBox::<T>::into_raw` for the same `T`, and
+    /// ownership of the value is transferred back to the returned `NonNullMaybeBox<T>`. This
+    /// means that `from_raw` can be called at most once for each call to `into_raw`.
+    pub unsafe fn from_raw(raw: NonNull<c_void>) -> NonNullMaybeBox<T> {
+        NonNullMaybeBox {
+            data: raw.cast(),
+            _ph: PhantomData,
+        }
+    }
+
+    fn word(&self) -> &MaybeUninit<usize> {
+        unsafe { &*(&self.data as *const NonNull<()> as *const MaybeUninit<usize>) }
+    }
+
+    fn word_mut(&mut self) -> &mut MaybeUninit<usize> {
+        unsafe { &mut *(&mut self.data as *mut NonNull<()> as *mut MaybeUninit<usize>) }
+    }
+}
+
+impl<T: NeverZero> Drop for NonNullMaybeBox<T> {
+    fn drop(&mut self) {
+        let _: Unpacked<T> = unsafe { read_data(self.word_mut()) };
+    }
+}
+
+impl<T: NeverZero> From<T> for NonNullMaybeBox<T> {
+    fn from(t: T) -> NonNullMaybeBox<T> {
+        NonNullMaybeBox::new(t)
+    }
+}
+
+impl<T: NeverZero> Deref for NonNullMaybeBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*data_ptr::<T, usize>(self.word()) }
+    }
+}
+
+impl<T: NeverZero> DerefMut for NonNullMaybeBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *data_ptr_mut::<T, usize>(self.word_mut()) }
+    }
+}
+
+impl<T: NeverZero + fmt::Debug> fmt::Debug for NonNullMaybeBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner: &T = self;
+        f.debug_tuple("NonNullMaybeBox").field(inner).finish()
+    }
+}
+
+impl<T: NeverZero + PartialEq> PartialEq for NonNullMaybeBox<T> {
+    fn eq(&self, other: &NonNullMaybeBox<T>) -> bool {
+        let l: &T = self;
+        let r: &T = other;
+        *l == *r
+    }
+}
+
+impl<T: NeverZero + Eq> Eq for NonNullMaybeBox<T> {}
+
+impl<T: NeverZero + hash::Hash> hash::Hash for NonNullMaybeBox<T> {
+    fn hash<H>(&self, state: &mut H)
+        where H: hash::Hasher
+    {
+        let inner: &T = self;
+        T::hash(inner, state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn inline_and_boxed() {
+        let mut mb = NonNullMaybeBox::new(NonZeroU16::new(7).unwrap());
+        assert_eq!(mb.get(), 7);
+        *mb = NonZeroU16::new(8).unwrap();
+        match mb.unpack() {
+            Unpacked::Inline(n) => assert_eq!(n.get(), 8),
+            x => panic!("Unexpected!: {:?}", x),
+        };
+
+        let s = String::from("hello");
+        let mb = Some(NonNullMaybeBox::new(&s[..]));
+        assert_eq!(mb.as_deref(), Some(&"hello"));
+
+        let rc = Rc::new(5u32);
+        let mb = NonNullMaybeBox::new(rc.clone());
+        assert_eq!(Rc::strong_count(&rc), 2);
+        let raw = mb.into_raw();
+        let mb = unsafe { NonNullMaybeBox::<Rc<u32>>::from_raw(raw) };
+        assert_eq!(**mb, 5);
+        drop(mb);
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let mb = NonNullMaybeBox::new(Box::new(0u64));
+        assert_eq!(**mb, 0);
+
+        let mb = NonNullMaybeBox::new(());
+        assert_eq!(mb.into_raw().as_ptr() as usize, 1);
+    }
+}
@@ -0,0 +1,283 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::os::raw::c_void;
+use std::ptr;
+
+use {fits_inline, write_data, data_ptr, data_ptr_mut, read_raw, data_from_raw};
+
+/// Hold an `Option<T>` in the space for a `usize`, only boxing it if necessary.
+///
+/// A `MaybeBox<Option<T>>` boxes the whole `Option` if it doesn't fit in a word, even if it's
+/// `None`. A `MaybeBoxOption<T>` instead represents `None` by a null word and only allocates for
+/// `Some`. If `Option<T>` does fit in a word then it's stored inline, exactly as in a
+/// `MaybeBox<Option<T>>`, though `into_raw` boxes it.
+///
+/// This type is guaranteed to be the same size as a `usize`.
+#[repr(transparent)]
+pub struct MaybeBoxOption<T> {
+    data: MaybeUninit<usize>,
+    _ph: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for MaybeBoxOption<T> {}
+unsafe impl<T: Sync> Sync for MaybeBoxOption<T> {}
+
+/// Whether `MaybeBoxOption<T>` stores the whole `Option<T>` inline. Otherwise the word is either
+/// null or a pointer to a boxed `T`.
+#[inline]
+const fn option_inline<T>() -> bool {
+    fits_inline::<Option<T>, usize>()
+}
+
+impl<T> MaybeBoxOption<T> {
+    /// Wrap an `Option<T>` into a `MaybeBoxOption<T>`. This only allocates if `opt` is `Some` and
+    /// `Option<T>` doesn't fit in a `usize`.
+    #[inline]
+    pub fn new(opt: Option<T>) -> MaybeBoxOption<T> {
+        let mut data = MaybeUninit::zeroed();
+        unsafe {
+            if option_inline::<T>() {
+                write_data::<Option<T>, usize>(opt, &mut data);
+            } else if let Some(t) = opt {
+                ptr::write(data.as_mut_ptr() as *mut *mut T, Box::into_raw(Box::new(t)));
+            }
+        }
+        MaybeBoxOption {
+            data,
+            _ph: PhantomData,
+        }
+    }
+
+    /// Create an empty `MaybeBoxOption<T>`. This never allocates.
+    #[inline]
+    pub fn none() -> MaybeBoxOption<T> {
+        MaybeBoxOption::new(None)
+    }
+
+    /// Get the pointer to the boxed `T`, or null if the slot is empty. Only valid if
+    /// `!option_inline::<T>()`.
+    #[inline]
+    unsafe fn boxed_ptr(&self) -> *mut T {
+        ptr::read(self.data.as_ptr() as *const *mut T)
+    }
+
+    #[inline]
+    unsafe fn inline_option(&self) -> &Option<T> {
+        &*data_ptr::<Option<T>, usize>(&self.data)
+    }
+
+    #[inline]
+    unsafe fn inline_option_mut(&mut self) -> &mut Option<T> {
+        &mut *data_ptr_mut::<Option<T>, usize>(&mut self.data)
+    }
+
+    /// Whether the slot holds a value.
+    pub fn is_some(&self) -> bool {
+        self.as_ref().is_some()
+    }
+
+    /// Whether the slot is empty.
+    pub fn is_none(&self) -> bool {
+        self.as_ref().is_none()
+    }
+
+    /// Borrow the value in the slot, if there is one.
+    pub fn as_ref(&self) -> Option<&T> {
+        unsafe {
+            if option_inline::<T>() {
+                self.inline_option().as_ref()
+            } else {
+                self.boxed_ptr().as_ref()
+            }
+        }
+    }
+
+    /// Mutably borrow the value in the slot, if there is one.
+    pub fn as_mut(&mut self) -> Option<&mut T> {
+        unsafe {
+            if option_inline::<T>() {
+                self.inline_option_mut().as_mut()
+            } else {
+                self.boxed_ptr().as_mut()
+            }
+        }
+    }
+
+    /// Take the value out of the slot, leaving it empty.
+    pub fn take(&mut self) -> Option<T> {
+        unsafe {
+            if option_inline::<T>() {
+                self.inline_option_mut().take()
+            } else {
+                let ptr = self.boxed_ptr();
+                if ptr.is_null() {
+                    None
+                } else {
+                    ptr::write(self.data.as_mut_ptr(), 0);
+                    Some(*Box::from_raw(ptr))
+                }
+            }
+        }
+    }
+
+    /// Put `t` in the slot, returning the value that was there before. If the slot already held a
+    /// boxed value then its allocation is reused.
+    pub fn replace(&mut self, t: T) -> Option<T> {
+        if let Some(old) = self.as_mut() {
+            return Some(mem::replace(old, t));
+        }
+        *self = MaybeBoxOption::new(Some(t));
+        None
+    }
+
+    /// Get a mutable reference to the value in the slot, first filling it with the result of `f`
+    /// if it's empty.
+    pub fn get_or_insert_with<F: FnOnce() -> T>(&mut self, f: F) -> &mut T {
+        if self.is_none() {
+            *self = MaybeBoxOption::new(Some(f()));
+        }
+        match self.as_mut() {
+            Some(t) => t,
+            None => unreachable!(),
+        }
+    }
+
+    /// Consume the `MaybeBoxOption<T>` and return the `Option<T>`.
+    pub fn into_inner(mut self) -> Option<T> {
+        let opt = self.take();
+        mem::forget(self);
+        opt
+    }
+
+    /// Consume the `MaybeBoxOption<T>` and return a word for passing to C code as a `void *`.
+    /// `None` is represented by a null pointer and `Some` by a pointer to the boxed value. If
+    /// `Option<T>` fits in a word then this boxes a `Some` value, since the inline `Option<T>` may
+    /// have uninitialized bytes.
+    ///
+    /// The word must eventually be turned back into a `MaybeBoxOption<T>` with `from_raw`. See the
+    /// crate docs on raw words.
+    pub fn into_raw(self) -> *mut c_void {
+        if option_inline::<T>() {
+            return match self.into_inner() {
+                Some(t) => Box::into_raw(Box::new(t)) as *mut c_void,
+                None => ptr::null_mut(),
+            };
+        }
+        let this = ManuallyDrop::new(self);
+        unsafe { read_raw::<*mut T, _>(this.data.as_ptr() as *const *mut c_void) }
+    }
+
+    /// Reconstruct a `MaybeBoxOption<T>` from a word returned by `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBoxOption::<T>::into_raw` for the same `T`, and not
+    /// already turned back into a `MaybeBoxOption<T>`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> MaybeBoxOption<T> {
+        if option_inline::<T>() {
+            if raw.is_null() {
+                return MaybeBoxOption::none();
+            }
+            return MaybeBoxOption::new(Some(*Box::from_raw(raw as *mut T)));
+        }
+        MaybeBoxOption {
+            data: data_from_raw(raw),
+            _ph: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for MaybeBoxOption<T> {
+    fn drop(&mut self) {
+        let _: Option<T> = self.take();
+    }
+}
+
+impl<T> Default for MaybeBoxOption<T> {
+    fn default() -> MaybeBoxOption<T> {
+        MaybeBoxOption::none()
+    }
+}
+
+impl<T> From<Option<T>> for MaybeBoxOption<T> {
+    fn from(opt: Option<T>) -> MaybeBoxOption<T> {
+        MaybeBoxOption::new(opt)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for MaybeBoxOption<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("MaybeBoxOption").field(&self.as_ref()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn big() {
+        let mut slot = MaybeBoxOption::<[u64; 4]>::none();
+        assert!(slot.is_none());
+        assert_eq!(slot.take(), None);
+        assert!(slot.into_raw().is_null());
+
+        let mut slot = MaybeBoxOption::new(Some([1u64, 2, 3, 4]));
+        assert_eq!(slot.as_ref(), Some(&[1, 2, 3, 4]));
+        let ptr = slot.as_ref().unwrap() as *const [u64; 4];
+        assert_eq!(slot.replace([5, 6, 7, 8]), Some([1, 2, 3, 4]));
+        assert_eq!(slot.as_ref().unwrap() as *const [u64; 4], ptr);
+        assert_eq!(slot.take(), Some([5, 6, 7, 8]));
+        assert!(slot.is_none());
+
+        slot.get_or_insert_with(|| [0; 4])[1] = 9;
+        assert_eq!(*slot.get_or_insert_with(|| unreachable!()), [0, 9, 0, 0]);
+        let raw = slot.into_raw();
+        let slot = unsafe { MaybeBoxOption::<[u64; 4]>::from_raw(raw) };
+        assert_eq!(format!("{:?}", slot), "MaybeBoxOption(Some([0, 9, 0, 0]))");
+        assert_eq!(slot.into_inner(), Some([0, 9, 0, 0]));
+    }
+
+    #[test]
+    fn small() {
+        let mut slot = MaybeBoxOption::new(Some(3u16));
+        assert_eq!(slot.replace(4), Some(3));
+        *slot.as_mut().unwrap() += 1;
+        assert_eq!(slot.take(), Some(5));
+        assert_eq!(slot.replace(6), None);
+        assert_eq!(slot.into_inner(), Some(6));
+
+        let mut slot = MaybeBoxOption::<()>::default();
+        assert!(slot.is_none());
+        slot.get_or_insert_with(|| ());
+        assert!(slot.is_some());
+        let slot = unsafe { MaybeBoxOption::<()>::from_raw(slot.into_raw()) };
+        assert_eq!(slot.into_inner(), Some(()));
+
+        // An inline `Option<u16>` has uninitialized bytes, so it's boxed on the way out.
+        assert!(MaybeBoxOption::<u16>::none().into_raw().is_null());
+        let raw = MaybeBoxOption::new(Some(7u16)).into_raw();
+        assert_eq!(unsafe { *(raw as *const u16) }, 7);
+        let slot = unsafe { MaybeBoxOption::<u16>::from_raw(raw) };
+        assert_eq!(slot.into_inner(), Some(7));
+    }
+
+    #[test]
+    fn drops() {
+        let rc = Rc::new(());
+        {
+            let mut slot = MaybeBoxOption::from(Some((rc.clone(), 0u64)));
+            assert_eq!(Rc::strong_count(&rc), 2);
+            slot.replace((rc.clone(), 1));
+            assert_eq!(Rc::strong_count(&rc), 2);
+        }
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let slot = MaybeBoxOption::new(Some(rc.clone()));
+        assert_eq!(Rc::strong_count(&rc), 2);
+        drop(slot);
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+}
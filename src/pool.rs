@@ -0,0 +1,389 @@
+//! A `MaybeBox` which recycles the heap allocations of boxed values through a thread-local pool.
+//!
+//! Creating and dropping many boxed `MaybeBox<T>`s spends much of its time in the allocator. A
+//! `PooledMaybeBox<T>` stores its value just like a `MaybeBox<T>`, but when a boxed one is dropped
+//! its heap cell is kept in a free list belonging to the current thread rather than being freed,
+//! and the next `PooledMaybeBox::new` on that thread which needs a cell of the same layout reuses
+//! it. If the free list is empty, cells come from the global allocator.
+//!
+//! There's one free list per layout on each thread, holding at most `capacity()` cells. Once it's
+//! full, further cells are freed as normal. The cells are ordinary global allocations, so a
+//! `PooledMaybeBox<T>` can be sent to and dropped on another thread, which then adopts its cell,
+//! and can be converted to and from a `MaybeBox<T>` without reallocating. A thread's cached cells
+//! are freed when it exits, or by calling `clear`.
+
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::ptr::{self, NonNull};
+
+use {data_ptr_mut, fits_inline, MaybeBox, NoUninit};
+
+/// The number of cells each free list holds unless changed with `set_capacity`.
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// Statistics for the free list of one layout on the current thread. Returned by `stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of cells that were reused from the free list.
+    pub hits: usize,
+    /// The number of cells that had to be allocated because the free list was empty.
+    pub misses: usize,
+    /// The number of cells that were freed because the free list was full.
+    pub freed: usize,
+    /// The number of cells currently in the free list.
+    pub cached: usize,
+}
+
+struct FreeList {
+    layout: Layout,
+    cells: Vec<NonNull<u8>>,
+    stats: Stats,
+}
+
+struct Pools {
+    capacity: usize,
+    lists: Vec<FreeList>,
+}
+
+impl Pools {
+    /// Get the free list for `layout`, creating it if there isn't one yet.
+    fn list(&mut self, layout: Layout) -> &mut FreeList {
+        let index = match self.lists.iter().position(|list| list.layout == layout) {
+            Some(index) => index,
+            None => {
+                self.lists.push(FreeList {
+                    layout,
+                    cells: Vec::new(),
+                    stats: Stats::default(),
+                });
+                self.lists.len() - 1
+            },
+        };
+        &mut self.lists[index]
+    }
+
+    /// Free all the cached cells.
+    fn clear(&mut self) {
+        for list in &mut self.lists {
+            for cell in list.cells.drain(..) {
+                unsafe { alloc::dealloc(cell.as_ptr(), list.layout) };
+            }
+            list.stats.cached = 0;
+        }
+    }
+}
+
+impl Drop for Pools {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+thread_local! {
+    static POOLS: RefCell<Pools> = const {
+        RefCell::new(Pools {
+            capacity: DEFAULT_CAPACITY,
+            lists: Vec::new(),
+        })
+    };
+}
+
+/// Get a cell for a value with the given layout, which must have a non-zero size.
+fn alloc_cell(layout: Layout) -> NonNull<u8> {
+    // The pool is gone if this is called from another thread-local's destructor.
+    let cell = POOLS.try_with(|pools| {
+        let mut pools = pools.borrow_mut();
+        let list = pools.list(layout);
+        match list.cells.pop() {
+            Some(cell) => {
+                list.stats.hits += 1;
+                list.stats.cached -= 1;
+                Some(cell)
+            },
+            None => {
+                list.stats.misses += 1;
+                None
+            },
+        }
+    });
+    if let Ok(Some(cell)) = cell {
+        return cell;
+    }
+    match NonNull::new(unsafe { alloc::alloc(layout) }) {
+        Some(cell) => cell,
+        None => alloc::handle_alloc_error(layout),
+    }
+}
+
+/// Give back a cell that was allocated for a value with the given layout.
+unsafe fn free_cell(cell: NonNull<u8>, layout: Layout) {
+    let kept = POOLS.try_with(|pools| {
+        let mut pools = pools.borrow_mut();
+        let capacity = pools.capacity;
+        let list = pools.list(layout);
+        if list.cells.len() < capacity {
+            list.cells.push(cell);
+            list.stats.cached += 1;
+            true
+        } else {
+            list.stats.freed += 1;
+            false
+        }
+    });
+    if kept != Ok(true) {
+        alloc::dealloc(cell.as_ptr(), layout);
+    }
+}
+
+/// Gives back a cell when dropped, so that it isn't leaked if the destructor of the value in it
+/// panics.
+struct FreeOnDrop {
+    cell: NonNull<u8>,
+    layout: Layout,
+}
+
+impl Drop for FreeOnDrop {
+    fn drop(&mut self) {
+        unsafe { free_cell(self.cell, self.layout) }
+    }
+}
+
+/// Get the maximum number of cells each free list on the current thread holds.
+pub fn capacity() -> usize {
+    POOLS.with(|pools| pools.borrow().capacity)
+}
+
+/// Set the maximum number of cells each free list on the current thread holds. Cells beyond the
+/// new capacity are freed.
+pub fn set_capacity(capacity: usize) {
+    POOLS.with(|pools| {
+        let mut pools = pools.borrow_mut();
+        pools.capacity = capacity;
+        for list in &mut pools.lists {
+            while list.cells.len() > capacity {
+                let cell = list.cells.pop().unwrap();
+                unsafe { alloc::dealloc(cell.as_ptr(), list.layout) };
+                list.stats.cached -= 1;
+            }
+        }
+    })
+}
+
+/// Get the statistics for the current thread's free list of cells for `T`s. Free lists are
+/// shared by all types with the same layout.
+pub fn stats<T>() -> Stats {
+    POOLS.with(|pools| {
+        let pools = pools.borrow();
+        let layout = Layout::new::<T>();
+        match pools.lists.iter().find(|list| list.layout == layout) {
+            Some(list) => list.stats,
+            None => Stats::default(),
+        }
+    })
+}
+
+/// Free all the cells cached by the current thread.
+pub fn clear() {
+    POOLS.with(|pools| pools.borrow_mut().clear())
+}
+
+/// Hold a value of type `T` in the space for a `usize`, only boxing it if necessary, and reusing
+/// heap cells from the current thread's pool.
+///
+/// This type is guaranteed to be the same size as a `usize`.
+#[repr(transparent)]
+pub struct PooledMaybeBox<T> {
+    inner: ManuallyDrop<MaybeBox<T>>,
+}
+
+impl<T> PooledMaybeBox<T> {
+    /// Wrap a `T` into a `PooledMaybeBox<T>`. If the `T` doesn't fit in a `usize` then it's stored
+    /// in a cell taken from the current thread's pool, or allocated if the pool is empty.
+    #[inline]
+    pub fn new(t: T) -> PooledMaybeBox<T> {
+        if fits_inline::<T, usize>() {
+            return PooledMaybeBox::from(MaybeBox::new(t));
+        }
+        unsafe {
+            let cell = alloc_cell(Layout::new::<T>()).as_ptr() as *mut T;
+            ptr::write(cell, t);
+            PooledMaybeBox::from(MaybeBox::from_box_ptr(cell))
+        }
+    }
+
+    /// Consume the `PooledMaybeBox<T>` and return the inner `T`. If the `T` was boxed then its cell
+    /// goes back to the current thread's pool.
+    pub fn into_inner(self) -> T {
+        let mut this = ManuallyDrop::new(self);
+        unsafe {
+            let mb = ManuallyDrop::take(&mut this.inner);
+            if fits_inline::<T, usize>() {
+                return mb.into_inner();
+            }
+            let cell = data_ptr_mut::<T, usize>(&mut mb.into_data());
+            let t = ptr::read(cell);
+            free_cell(NonNull::new_unchecked(cell as *mut u8), Layout::new::<T>());
+            t
+        }
+    }
+
+    /// Convert the `PooledMaybeBox<T>` into a `MaybeBox<T>`. A boxed value keeps its cell, which
+    /// is freed rather than going back to a pool when the `MaybeBox<T>` is dropped.
+    pub fn into_maybe_box(self) -> MaybeBox<T> {
+        let mut this = ManuallyDrop::new(self);
+        unsafe { ManuallyDrop::take(&mut this.inner) }
+    }
+}
+
+impl<T: NoUninit> PooledMaybeBox<T> {
+    /// Consume the `PooledMaybeBox<T>` and return the word it's stored in, for passing to C code as
+    /// a `void *`. This is the same as `MaybeBox::into_raw`.
+    ///
+    /// The word must eventually be turned back into a `PooledMaybeBox<T>` with `from_raw`. See the
+    /// crate docs on raw words.
+    pub fn into_raw(self) -> *mut c_void {
+        self.into_maybe_box().into_raw()
+    }
+}
+
+impl<T> PooledMaybeBox<T> {
+    /// The same as `into_raw`, for a `T` that's too big to be stored inline, so that it doesn't
+    /// need to implement `NoUninit`. This is the same as `MaybeBox::into_boxed_raw`, and fails to
+    /// compile if a `T` would be stored inline.
+    pub fn into_boxed_raw(self) -> *mut c_void {
+        self.into_maybe_box().into_boxed_raw()
+    }
+
+    /// Reconstruct a `PooledMaybeBox<T>` from a word returned by `into_raw` or `into_boxed_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by the `into_raw` or `into_boxed_raw` method of
+    /// `PooledMaybeBox<T>` or `MaybeBox<T>` for the same `T`, and not already turned back into a
+    /// `PooledMaybeBox<T>` or a `MaybeBox<T>`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> PooledMaybeBox<T> {
+        PooledMaybeBox::from(MaybeBox::from_raw(raw))
+    }
+}
+
+impl<T> Drop for PooledMaybeBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let mb = ManuallyDrop::take(&mut self.inner);
+            if fits_inline::<T, usize>() {
+                drop(mb);
+                return;
+            }
+            let cell = data_ptr_mut::<T, usize>(&mut mb.into_data());
+            let _free = FreeOnDrop {
+                cell: NonNull::new_unchecked(cell as *mut u8),
+                layout: Layout::new::<T>(),
+            };
+            ptr::drop_in_place(cell);
+        }
+    }
+}
+
+/// Adopts the `MaybeBox<T>`'s heap cell, if it has one, so that it goes to the pool when the
+/// `PooledMaybeBox<T>` is dropped.
+impl<T> From<MaybeBox<T>> for PooledMaybeBox<T> {
+    fn from(mb: MaybeBox<T>) -> PooledMaybeBox<T> {
+        PooledMaybeBox {
+            inner: ManuallyDrop::new(mb),
+        }
+    }
+}
+
+impl<T> Deref for PooledMaybeBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for PooledMaybeBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PooledMaybeBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner: &T = self;
+        f.debug_tuple("PooledMaybeBox").field(inner).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+    use std::thread;
+
+    #[test]
+    fn reuses_cells() {
+        // Each test runs on its own thread, so has its own pools.
+        let mb = PooledMaybeBox::new([1u64; 8]);
+        let cell = &*mb as *const [u64; 8];
+        drop(mb);
+        let mb = PooledMaybeBox::new([2u64; 8]);
+        assert_eq!(&*mb as *const [u64; 8], cell);
+        assert_eq!(mb.into_inner(), [2; 8]);
+
+        let s = stats::<[u64; 8]>();
+        assert_eq!((s.hits, s.misses, s.freed, s.cached), (1, 1, 0, 1));
+        // Types with the same layout share a free list.
+        assert_eq!(stats::<[i64; 8]>(), s);
+        let mb = PooledMaybeBox::new(3u32);
+        assert_eq!(*mb, 3);
+        assert_eq!(stats::<u32>(), Stats::default());
+
+        clear();
+        assert_eq!(stats::<[u64; 8]>().cached, 0);
+    }
+
+    #[test]
+    fn capacity_and_conversions() {
+        assert_eq!(capacity(), DEFAULT_CAPACITY);
+        set_capacity(2);
+        let boxes: Vec<_> = (0..4usize).map(|i| PooledMaybeBox::new([i; 4])).collect();
+        drop(boxes);
+        let s = stats::<[usize; 4]>();
+        assert_eq!((s.misses, s.freed, s.cached), (4, 2, 2));
+        set_capacity(1);
+        assert_eq!(stats::<[usize; 4]>().cached, 1);
+
+        let mb = PooledMaybeBox::from(MaybeBox::new([5usize; 4]));
+        let raw = mb.into_raw();
+        let mut mb = unsafe { PooledMaybeBox::<[usize; 4]>::from_raw(raw) };
+        mb[0] = 6;
+        assert_eq!(format!("{:?}", mb), "PooledMaybeBox([6, 5, 5, 5])");
+        assert_eq!(mb.into_maybe_box().into_inner(), [6, 5, 5, 5]);
+
+        let mb = PooledMaybeBox::new([7usize; 4]);
+        thread::spawn(move || drop(mb)).join().unwrap();
+        assert_eq!(stats::<[usize; 4]>().cached, 0);
+    }
+
+    #[test]
+    fn drops() {
+        let rc = Rc::new(());
+        let mb = PooledMaybeBox::new((rc.clone(), [0u8; 64]));
+        let small = PooledMaybeBox::new(rc.clone());
+        assert_eq!(Rc::strong_count(&rc), 3);
+        drop((mb, small));
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let raw = PooledMaybeBox::new((rc.clone(), [0u8; 64])).into_boxed_raw();
+        let mb = unsafe { PooledMaybeBox::<(Rc<()>, [u8; 64])>::from_raw(raw) };
+        assert_eq!(Rc::strong_count(&rc), 2);
+        drop(mb);
+        assert_eq!(Rc::strong_count(&rc), 1);
+        assert_eq!(stats::<(Rc<()>, [u8; 64])>().cached, 1);
+    }
+}
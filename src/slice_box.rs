@@ -0,0 +1,432 @@
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::hash;
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use {is_zst, read_raw, read_boxed_raw, data_from_raw, NoUninit};
+use tagged::{tag_offset, value_offset};
+
+/// The offset of the byte holding the length of an inline slice.
+const LEN_BYTE: usize = tag_offset(8);
+
+/// The size of the length prefix of a boxed slice of `T`s, including any padding before the
+/// elements.
+#[inline]
+const fn header_size<T>() -> usize {
+    mem::size_of::<usize>().next_multiple_of(mem::align_of::<T>())
+}
+
+/// The layout of the heap allocation for a boxed slice of `len` `T`s: a `usize` length followed
+/// by the elements.
+#[inline]
+fn boxed_layout<T>(len: usize) -> Layout {
+    let array = match Layout::array::<T>(len) {
+        Ok(array) => array,
+        Err(_) => panic!("slice too long"),
+    };
+    match Layout::new::<usize>().extend(array) {
+        Ok((layout, _)) => layout.pad_to_align(),
+        Err(_) => panic!("slice too long"),
+    }
+}
+
+/// Whether the heap allocation of a boxed slice of `T`s can be handed over to a `Vec<T>` once the
+/// elements are moved to its start. This needs the allocation to be aligned exactly for `T`, and
+/// the length prefix to take up a whole number of `T`s.
+#[inline]
+const fn reusable_by_vec<T>() -> bool {
+    !is_zst::<T>() &&
+    mem::align_of::<T>() >= mem::align_of::<usize>() &&
+    header_size::<T>() % mem::size_of::<T>() == 0
+}
+
+/// Hold a slice in the space for a `usize`, only boxing it if necessary.
+///
+/// Slices of up to `MAX_INLINE_LEN` elements are stored inline, with their length in the least
+/// significant byte of the word. This is 7 `u8`s or 3 `u16`s on a target with 64-bit words. Longer
+/// slices are boxed along with their length, so the word is a thin pointer. The lowest bit of the
+/// word is set for inline slices and clear for boxed ones.
+///
+/// This type is guaranteed to be the same size as a `usize`.
+#[repr(transparent)]
+pub struct MaybeBoxSlice<T> {
+    data: MaybeUninit<usize>,
+    _ph: PhantomData<Box<[T]>>,
+}
+
+unsafe impl<T: Send> Send for MaybeBoxSlice<T> {}
+unsafe impl<T: Sync> Sync for MaybeBoxSlice<T> {}
+
+/// A `MaybeBoxSlice` which is being filled in. If it's dropped, which only happens if cloning an
+/// element panics, the elements written so far are dropped and the slice is freed.
+struct PartialSlice<T> {
+    slice: ManuallyDrop<MaybeBoxSlice<T>>,
+    written: usize,
+}
+
+impl<T> Drop for PartialSlice<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let elems = MaybeBoxSlice::<T>::elems_ptr(&mut self.slice.data);
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(elems, self.written));
+            self.slice.free();
+        }
+    }
+}
+
+impl<T> MaybeBoxSlice<T> {
+    /// The length of the longest slice that's stored inline.
+    pub const MAX_INLINE_LEN: usize = {
+        let end = if cfg!(target_endian = "little") {
+            mem::size_of::<usize>()
+        } else {
+            LEN_BYTE
+        };
+        if is_zst::<T>() {
+            (u8::MAX >> 1) as usize
+        } else if mem::align_of::<T>() > mem::align_of::<usize>() {
+            0
+        } else {
+            (end - value_offset::<T>(8)) / mem::size_of::<T>()
+        }
+    };
+
+    /// Create a `MaybeBoxSlice<T>` of length `len`, allocating if necessary, without initializing
+    /// its elements.
+    unsafe fn uninit(len: usize) -> MaybeBoxSlice<T> {
+        let mut data = MaybeUninit::<usize>::zeroed();
+        if len <= MaybeBoxSlice::<T>::MAX_INLINE_LEN {
+            *(data.as_mut_ptr() as *mut u8).add(LEN_BYTE) = ((len as u8) << 1) | 1;
+        } else {
+            let layout = boxed_layout::<T>(len);
+            let ptr = alloc::alloc(layout);
+            if ptr.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            ptr::write(ptr as *mut usize, len);
+            ptr::write(data.as_mut_ptr() as *mut *mut u8, ptr);
+        }
+        MaybeBoxSlice {
+            data,
+            _ph: PhantomData,
+        }
+    }
+
+    /// Whether the slice is stored inline.
+    pub fn is_inline(&self) -> bool {
+        // The length byte is always initialized, whether the slice is inline or boxed.
+        unsafe { *(self.data.as_ptr() as *const u8).add(LEN_BYTE) & 1 == 1 }
+    }
+
+    /// Get the pointer to the heap allocation of a boxed slice.
+    #[inline]
+    unsafe fn boxed_ptr(&self) -> *mut u8 {
+        ptr::read(self.data.as_ptr() as *const *mut u8)
+    }
+
+    /// Get the number of elements.
+    #[inline]
+    fn stored_len(&self) -> usize {
+        unsafe {
+            if self.is_inline() {
+                (*(self.data.as_ptr() as *const u8).add(LEN_BYTE) >> 1) as usize
+            } else {
+                ptr::read(self.boxed_ptr() as *const usize)
+            }
+        }
+    }
+
+    /// Get a pointer to the first element of the slice stored in the word at `data`. If no
+    /// elements fit inline, which is the case when `T` is more aligned than a `usize`, then an
+    /// inline slice is empty and this is dangling rather than pointing past the word.
+    #[inline]
+    unsafe fn elems_ptr(data: *mut MaybeUninit<usize>) -> *mut T {
+        let inline = *(data as *const u8).add(LEN_BYTE) & 1 == 1;
+        if is_zst::<T>() || (inline && MaybeBoxSlice::<T>::MAX_INLINE_LEN == 0) {
+            NonNull::dangling().as_ptr()
+        } else if inline {
+            (data as *mut u8).add(value_offset::<T>(8)) as *mut T
+        } else {
+            ptr::read(data as *const *mut u8).add(header_size::<T>()) as *mut T
+        }
+    }
+
+    /// Free the heap allocation, if there is one, without dropping the elements.
+    unsafe fn free(&mut self) {
+        if !self.is_inline() {
+            alloc::dealloc(self.boxed_ptr(), boxed_layout::<T>(self.stored_len()));
+        }
+    }
+
+    /// Move the elements of a `Vec<T>` into a `MaybeBoxSlice<T>`. This will allocate if there are
+    /// more than `MAX_INLINE_LEN` of them.
+    pub fn from_vec(mut vec: Vec<T>) -> MaybeBoxSlice<T> {
+        let len = vec.len();
+        unsafe {
+            let mut ret = MaybeBoxSlice::uninit(len);
+            ptr::copy_nonoverlapping(vec.as_ptr(), MaybeBoxSlice::elems_ptr(&mut ret.data), len);
+            vec.set_len(0);
+            ret
+        }
+    }
+
+    /// Move the elements into a `Vec<T>`. If the slice is boxed, and the length prefix can become
+    /// spare capacity of the `Vec<T>`, the heap allocation is reused. This is the case when `T` is
+    /// at least as aligned as a `usize` and its size divides that of the prefix, eg. for `u64`.
+    pub fn into_vec(self) -> Vec<T> {
+        let mut this = ManuallyDrop::new(self);
+        let len = this.stored_len();
+        unsafe {
+            let elems = MaybeBoxSlice::elems_ptr(&mut this.data);
+            if reusable_by_vec::<T>() && !this.is_inline() {
+                let ptr = this.boxed_ptr() as *mut T;
+                ptr::copy(elems, ptr, len);
+                let capacity = len + header_size::<T>() / mem::size_of::<T>();
+                return Vec::from_raw_parts(ptr, len, capacity);
+            }
+            let mut vec = Vec::with_capacity(len);
+            ptr::copy_nonoverlapping(elems, vec.as_mut_ptr(), len);
+            vec.set_len(len);
+            this.free();
+            vec
+        }
+    }
+
+    /// Borrow the slice.
+    pub fn as_slice(&self) -> &[T] {
+        unsafe {
+            let elems = MaybeBoxSlice::elems_ptr(self.data.as_ptr() as *mut MaybeUninit<usize>);
+            slice::from_raw_parts(elems, self.stored_len())
+        }
+    }
+
+    /// Mutably borrow the slice.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        let len = self.stored_len();
+        unsafe { slice::from_raw_parts_mut(MaybeBoxSlice::elems_ptr(&mut self.data), len) }
+    }
+}
+
+impl<T: NoUninit> MaybeBoxSlice<T> {
+    /// Consume the `MaybeBoxSlice<T>` and return the word it's stored in, for passing to C code as
+    /// a `void *`.
+    ///
+    /// The word must eventually be turned back into a `MaybeBoxSlice<T>` with `from_raw`. See the
+    /// crate docs on raw words.
+    pub fn into_raw(self) -> *mut c_void {
+        let this = ManuallyDrop::new(self);
+        unsafe { read_raw::<T, _>(this.data.as_ptr() as *const *mut c_void) }
+    }
+}
+
+impl<T> MaybeBoxSlice<T> {
+    /// The same as `into_raw`, for a `T` that's never stored inline, so that it doesn't need to
+    /// implement `NoUninit`. Fails to compile unless `MAX_INLINE_LEN` is zero or `T` is
+    /// zero-sized.
+    pub fn into_boxed_raw(self) -> *mut c_void {
+        const {
+            assert!(
+                is_zst::<T>() || MaybeBoxSlice::<T>::MAX_INLINE_LEN == 0,
+                "elements are stored inline, so `into_boxed_raw` can't be used",
+            )
+        };
+        let this = ManuallyDrop::new(self);
+        unsafe { read_boxed_raw(this.data.as_ptr() as *const *mut c_void) }
+    }
+
+    /// Reconstruct a `MaybeBoxSlice<T>` from a word returned by `into_raw` or `into_boxed_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBoxSlice::<T>::into_raw` or
+    /// `MaybeBoxSlice::<T>::into_boxed_raw` for the same `T`, and not already turned back into a
+    /// `MaybeBoxSlice<T>`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> MaybeBoxSlice<T> {
+        MaybeBoxSlice {
+            data: data_from_raw(raw),
+            _ph: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for MaybeBoxSlice<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.as_mut_slice());
+            self.free();
+        }
+    }
+}
+
+impl<T> Deref for MaybeBoxSlice<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T> DerefMut for MaybeBoxSlice<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T> Default for MaybeBoxSlice<T> {
+    fn default() -> MaybeBoxSlice<T> {
+        MaybeBoxSlice::from_vec(Vec::new())
+    }
+}
+
+impl<T: Clone> Clone for MaybeBoxSlice<T> {
+    fn clone(&self) -> MaybeBoxSlice<T> {
+        MaybeBoxSlice::from(self.as_slice())
+    }
+}
+
+impl<T> From<Vec<T>> for MaybeBoxSlice<T> {
+    fn from(vec: Vec<T>) -> MaybeBoxSlice<T> {
+        MaybeBoxSlice::from_vec(vec)
+    }
+}
+
+impl<'a, T: Clone> From<&'a [T]> for MaybeBoxSlice<T> {
+    fn from(s: &'a [T]) -> MaybeBoxSlice<T> {
+        let mut partial = PartialSlice {
+            slice: ManuallyDrop::new(unsafe { MaybeBoxSlice::uninit(s.len()) }),
+            written: 0,
+        };
+        let elems = unsafe { MaybeBoxSlice::<T>::elems_ptr(&mut partial.slice.data) };
+        for x in s {
+            unsafe { ptr::write(elems.add(partial.written), x.clone()) };
+            partial.written += 1;
+        }
+        let partial = ManuallyDrop::new(partial);
+        unsafe { ptr::read(&*partial.slice) }
+    }
+}
+
+impl<T> From<MaybeBoxSlice<T>> for Vec<T> {
+    fn from(s: MaybeBoxSlice<T>) -> Vec<T> {
+        s.into_vec()
+    }
+}
+
+impl<T> FromIterator<T> for MaybeBoxSlice<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> MaybeBoxSlice<T> {
+        MaybeBoxSlice::from_vec(iter.into_iter().collect())
+    }
+}
+
+impl<T: PartialEq> PartialEq for MaybeBoxSlice<T> {
+    fn eq(&self, other: &MaybeBoxSlice<T>) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Eq> Eq for MaybeBoxSlice<T> {}
+
+impl<T: hash::Hash> hash::Hash for MaybeBoxSlice<T> {
+    fn hash<H>(&self, state: &mut H)
+        where H: hash::Hasher
+    {
+        self.as_slice().hash(state)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for MaybeBoxSlice<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("MaybeBoxSlice").field(&self.as_slice()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn inline_and_boxed() {
+        let word = mem::size_of::<usize>();
+        assert_eq!(MaybeBoxSlice::<u8>::MAX_INLINE_LEN, word - 1);
+        assert_eq!(MaybeBoxSlice::<u16>::MAX_INLINE_LEN, word / 2 - 1);
+        assert_eq!(MaybeBoxSlice::<usize>::MAX_INLINE_LEN, 0);
+
+        let mut s = MaybeBoxSlice::from(&[1u16, 2, 3][..word / 2 - 1]);
+        assert!(s.is_inline());
+        s[0] = 4;
+        assert_eq!(s[..2], [4, 2]);
+        assert_eq!(format!("{:?}", s.clone()), format!("MaybeBoxSlice({:?})", &s[..]));
+
+        let s: MaybeBoxSlice<u16> = (0..100).collect();
+        assert!(!s.is_inline());
+        let raw = s.into_raw();
+        assert_eq!(raw as usize & 1, 0);
+        let s = unsafe { MaybeBoxSlice::<u16>::from_raw(raw) };
+        assert_eq!(s.into_vec(), (0..100).collect::<Vec<_>>());
+
+        let s = MaybeBoxSlice::from(vec![(); 1000]);
+        assert!(!s.is_inline());
+        assert_eq!(s.len(), 1000);
+        assert_eq!(MaybeBoxSlice::<u8>::default().into_vec(), []);
+    }
+
+    #[test]
+    fn overaligned() {
+        #[derive(Clone, Debug, PartialEq)]
+        #[repr(align(32))]
+        struct A32(u8);
+
+        assert_eq!(MaybeBoxSlice::<A32>::MAX_INLINE_LEN, 0);
+        let mut s = MaybeBoxSlice::<A32>::from(Vec::new());
+        assert!(s.is_inline());
+        assert_eq!(s.as_ptr() as usize % 32, 0);
+        assert_eq!(s.as_mut_slice(), []);
+        assert_eq!(s.clone().into_vec(), []);
+
+        let s = MaybeBoxSlice::from(vec![A32(1), A32(2)]);
+        assert!(!s.is_inline());
+        assert_eq!(s.as_ptr() as usize % 32, 0);
+        assert_eq!(s.into_vec(), [A32(1), A32(2)]);
+    }
+
+    #[test]
+    fn into_vec_reuses_allocation() {
+        let s = MaybeBoxSlice::from(vec![1u64, 2, 3]);
+        let ptr = s.into_raw();
+        let s = unsafe { MaybeBoxSlice::<u64>::from_raw(ptr) };
+        let mut vec = s.into_vec();
+        assert_eq!(vec.as_ptr() as *mut c_void, ptr);
+        assert_eq!(vec, [1, 2, 3]);
+        vec.push(4);
+        assert_eq!(Vec::from(MaybeBoxSlice::from(vec)), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn drops() {
+        let rc = Rc::new(());
+        let s = MaybeBoxSlice::from(vec![rc.clone(); 5]);
+        let t = s.clone();
+        assert_eq!(Rc::strong_count(&rc), 11);
+        drop(s);
+        assert_eq!(t.into_vec().len(), 5);
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn boxed_raw() {
+        assert_eq!(MaybeBoxSlice::<String>::MAX_INLINE_LEN, 0);
+        for len in 0..3 {
+            let strings = vec![String::from("hello"); len];
+            let raw = MaybeBoxSlice::from(strings.clone()).into_boxed_raw();
+            let s = unsafe { MaybeBoxSlice::<String>::from_raw(raw) };
+            assert_eq!(s.into_vec(), strings);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash;
+use std::ops::Deref;
+use std::os::raw::c_void;
+use std::str;
+
+use slice_box::MaybeBoxSlice;
+
+/// Hold a string in the space for a `usize`, only boxing it if necessary.
+///
+/// Strings of up to `MAX_INLINE_LEN` bytes (`size_of::<usize>() - 1`) are stored inline, with their
+/// length in the least significant byte of the word. Longer strings are boxed along with their
+/// length, so the word is a thin pointer. The lowest bit of the word is set for inline strings and
+/// clear for boxed ones. This is the same representation as a `MaybeBoxSlice<u8>`.
+///
+/// This type is guaranteed to be the same size as a `usize`.
+#[repr(transparent)]
+#[derive(Clone, Default)]
+pub struct MaybeBoxStr {
+    bytes: MaybeBoxSlice<u8>,
+}
+
+impl MaybeBoxStr {
+    /// The length in bytes of the longest string that's stored inline.
+    pub const MAX_INLINE_LEN: usize = MaybeBoxSlice::<u8>::MAX_INLINE_LEN;
+
+    /// Copy a `&str` into a `MaybeBoxStr`. This will allocate if `s` is longer than
+    /// `MAX_INLINE_LEN` bytes.
+    pub fn new(s: &str) -> MaybeBoxStr {
+        MaybeBoxStr {
+            bytes: MaybeBoxSlice::from(s.as_bytes()),
+        }
+    }
+
+    /// Whether the string is stored inline.
+    pub fn is_inline(&self) -> bool {
+        self.bytes.is_inline()
+    }
+
+    /// Borrow the string.
+    pub fn as_str(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.bytes) }
+    }
+
+    /// Consume the `MaybeBoxStr` and return the word it's stored in, for passing to C code as a
+    /// `void *`.
+    ///
+    /// The word must eventually be turned back into a `MaybeBoxStr` with `from_raw`. See the crate
+    /// docs on raw words.
+    pub fn into_raw(self) -> *mut c_void {
+        self.bytes.into_raw()
+    }
+
+    /// Reconstruct a `MaybeBoxStr` from a word returned by `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `MaybeBoxStr::into_raw`, and not already turned back into a
+    /// `MaybeBoxStr`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> MaybeBoxStr {
+        MaybeBoxStr {
+            bytes: MaybeBoxSlice::from_raw(raw),
+        }
+    }
+}
+
+impl Deref for MaybeBoxStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for MaybeBoxStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<'a> From<&'a str> for MaybeBoxStr {
+    fn from(s: &'a str) -> MaybeBoxStr {
+        MaybeBoxStr::new(s)
+    }
+}
+
+impl From<String> for MaybeBoxStr {
+    fn from(s: String) -> MaybeBoxStr {
+        MaybeBoxStr::new(&s)
+    }
+}
+
+impl PartialEq for MaybeBoxStr {
+    fn eq(&self, other: &MaybeBoxStr) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for MaybeBoxStr {}
+
+impl PartialEq<str> for MaybeBoxStr {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for MaybeBoxStr {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialOrd for MaybeBoxStr {
+    fn partial_cmp(&self, other: &MaybeBoxStr) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MaybeBoxStr {
+    fn cmp(&self, other: &MaybeBoxStr) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl hash::Hash for MaybeBoxStr {
+    fn hash<H>(&self, state: &mut H)
+        where H: hash::Hasher
+    {
+        self.as_str().hash(state)
+    }
+}
+
+impl fmt::Display for MaybeBoxStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Debug for MaybeBoxStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("MaybeBoxStr").field(&self.as_str()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::{BTreeSet, HashSet};
+
+    #[test]
+    fn inline_and_boxed() {
+        let empty = MaybeBoxStr::default();
+        assert!(empty.is_inline());
+        assert_eq!(empty, "");
+
+        let long = "a".repeat(MaybeBoxStr::MAX_INLINE_LEN);
+        let s = MaybeBoxStr::new(&long);
+        assert!(s.is_inline());
+        assert_eq!(s.as_str(), long);
+        assert_eq!(s.into_raw() as usize & 1, 1);
+
+        let longer = MaybeBoxStr::from(long + "é");
+        assert!(!longer.is_inline());
+        assert_eq!(longer.len(), MaybeBoxStr::MAX_INLINE_LEN + 2);
+        assert!(longer.ends_with('é'));
+        let raw = longer.into_raw();
+        assert_eq!(raw as usize & 1, 0);
+        let longer = unsafe { MaybeBoxStr::from_raw(raw) };
+        assert_eq!(longer.clone(), longer);
+        assert_eq!(longer.to_string(), format!("{}é", "a".repeat(MaybeBoxStr::MAX_INLINE_LEN)));
+        assert_eq!(format!("{:?}", MaybeBoxStr::from("hi")), "MaybeBoxStr(\"hi\")");
+    }
+
+    #[test]
+    fn collections() {
+        let words = ["id", "name", "description", "", "name"];
+        let set: HashSet<MaybeBoxStr> = words.iter().map(|&w| MaybeBoxStr::from(w)).collect();
+        assert_eq!(set.len(), 4);
+        assert!(set.contains("description"));
+
+        let sorted: BTreeSet<MaybeBoxStr> = words.iter().map(|&w| MaybeBoxStr::from(w)).collect();
+        let sorted: Vec<&str> = sorted.iter().map(|s| s.as_str()).collect();
+        assert_eq!(sorted, ["", "description", "id", "name"]);
+    }
+}
@@ -0,0 +1,330 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::ptr::{self, NonNull};
+
+use {is_zst, read_raw, read_boxed_raw, data_from_raw, NoUninit};
+
+const WORD: usize = mem::size_of::<usize>();
+
+/// The number of bytes at the least-significant end of the word that hold the tag.
+#[inline]
+const fn tag_bytes(bits: u32) -> usize {
+    (bits as usize).div_ceil(8)
+}
+
+/// The offset in memory of the first byte holding the tag.
+#[inline]
+pub(crate) const fn tag_offset(bits: u32) -> usize {
+    if cfg!(target_endian = "little") {
+        0
+    } else {
+        WORD - tag_bytes(bits)
+    }
+}
+
+/// The offset in memory of an inline `T`. This is just past the tag bytes on little-endian targets
+/// and at the start of the word on big-endian targets.
+#[inline]
+pub(crate) const fn value_offset<T>(bits: u32) -> usize {
+    if cfg!(target_endian = "little") {
+        tag_bytes(bits).next_multiple_of(mem::align_of::<T>())
+    } else {
+        0
+    }
+}
+
+/// Whether a `T` can be stored in a word alongside a `bits`-bit tag without overlapping it.
+#[inline]
+pub(crate) const fn fits_tagged<T>(bits: u32) -> bool {
+    let end = if cfg!(target_endian = "little") {
+        WORD
+    } else {
+        tag_offset(bits)
+    };
+    is_zst::<T>() || (
+        mem::align_of::<T>() <= mem::align_of::<usize>() &&
+        value_offset::<T>(bits) + mem::size_of::<T>() <= end
+    )
+}
+
+/// Hold a value of type `T` along with a `BITS`-bit tag in the space for a `usize`, only boxing the
+/// value if necessary.
+///
+/// The tag occupies the low `BITS` bits of the word. If the value is boxed then the tag is stored
+/// in the low bits of the pointer, which are always zero since the allocation is aligned for `T`.
+/// So it's a compile-time error to create a `TaggedMaybeBox<T, BITS>` where `T` needs boxing and
+/// `align_of::<T>()` is less than `1 << BITS`. Otherwise the value is stored inline in the bytes of
+/// the word that the tag doesn't use, if there's room.
+///
+/// This type is guaranteed to be the same size as a `usize`.
+#[repr(transparent)]
+pub struct TaggedMaybeBox<T, const BITS: u32> {
+    data: MaybeUninit<usize>,
+    _ph: PhantomData<T>,
+}
+
+unsafe impl<T: Send, const BITS: u32> Send for TaggedMaybeBox<T, BITS> {}
+unsafe impl<T: Sync, const BITS: u32> Sync for TaggedMaybeBox<T, BITS> {}
+
+impl<T, const BITS: u32> TaggedMaybeBox<T, BITS> {
+    /// The largest tag that fits in `BITS` bits.
+    pub const MAX_TAG: usize = (1 << BITS) - 1;
+
+    /// Wrap a `T` and a tag into a `TaggedMaybeBox<T, BITS>`. This will allocate if `T` doesn't fit
+    /// in the bytes of a `usize` not needed for the tag. Zero-sized types never allocate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tag` is greater than `MAX_TAG`.
+    #[inline]
+    pub fn new(t: T, tag: usize) -> TaggedMaybeBox<T, BITS> {
+        const {
+            assert!(BITS < usize::BITS, "the tag must leave room in the word");
+            assert!(
+                fits_tagged::<T>(BITS) || mem::align_of::<T>() >= 1 << BITS,
+                "value doesn't fit inline and its alignment doesn't leave enough bits for the tag",
+            )
+        };
+
+        let mut data = MaybeUninit::<usize>::zeroed();
+        unsafe {
+            if is_zst::<T>() {
+                ptr::write(NonNull::<T>::dangling().as_ptr(), t);
+            } else if fits_tagged::<T>(BITS) {
+                let ptr = (data.as_mut_ptr() as *mut u8).add(value_offset::<T>(BITS));
+                ptr::write(ptr as *mut T, t);
+            } else {
+                ptr::write(data.as_mut_ptr() as *mut *mut T, Box::into_raw(Box::new(t)));
+            }
+        }
+        let mut ret = TaggedMaybeBox::<T, BITS> {
+            data,
+            _ph: PhantomData,
+        };
+        ret.set_tag(tag);
+        ret
+    }
+
+    /// Get the tag.
+    pub fn tag(&self) -> usize {
+        let max = TaggedMaybeBox::<T, BITS>::MAX_TAG;
+        unsafe {
+            if fits_tagged::<T>(BITS) {
+                let mut bytes = [0u8; WORD];
+                let offset = tag_offset(BITS);
+                ptr::copy_nonoverlapping(
+                    (self.data.as_ptr() as *const u8).add(offset),
+                    bytes.as_mut_ptr().add(offset),
+                    tag_bytes(BITS),
+                );
+                usize::from_ne_bytes(bytes) & max
+            } else {
+                ptr::read(self.data.as_ptr() as *const *mut T).addr() & max
+            }
+        }
+    }
+
+    /// Set the tag.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tag` is greater than `MAX_TAG`.
+    pub fn set_tag(&mut self, tag: usize) {
+        let max = TaggedMaybeBox::<T, BITS>::MAX_TAG;
+        assert!(tag <= max, "tag {} doesn't fit in {} bits", tag, BITS);
+        unsafe {
+            if fits_tagged::<T>(BITS) {
+                let bytes = tag.to_ne_bytes();
+                let offset = tag_offset(BITS);
+                ptr::copy_nonoverlapping(
+                    bytes.as_ptr().add(offset),
+                    (self.data.as_mut_ptr() as *mut u8).add(offset),
+                    tag_bytes(BITS),
+                );
+            } else {
+                let ptr = self.data.as_mut_ptr() as *mut *mut T;
+                ptr::write(ptr, ptr::read(ptr).map_addr(|addr| (addr & !max) | tag));
+            }
+        }
+    }
+
+    /// Get a pointer to the value stored in the word at `data`, with the tag masked off if it's
+    /// boxed.
+    #[inline]
+    unsafe fn value_ptr(data: *mut MaybeUninit<usize>) -> *mut T {
+        if is_zst::<T>() {
+            NonNull::dangling().as_ptr()
+        } else if fits_tagged::<T>(BITS) {
+            (data as *mut u8).add(value_offset::<T>(BITS)) as *mut T
+        } else {
+            let max = TaggedMaybeBox::<T, BITS>::MAX_TAG;
+            ptr::read(data as *const *mut T).map_addr(|addr| addr & !max)
+        }
+    }
+
+    /// Consume the `TaggedMaybeBox<T, BITS>` and return the inner `T` and the tag.
+    pub fn into_parts(self) -> (T, usize) {
+        let tag = self.tag();
+        let mut this = ManuallyDrop::new(self);
+        let t = unsafe {
+            let ptr = TaggedMaybeBox::<T, BITS>::value_ptr(&mut this.data);
+            if is_zst::<T>() || fits_tagged::<T>(BITS) {
+                ptr::read(ptr)
+            } else {
+                *Box::from_raw(ptr)
+            }
+        };
+        (t, tag)
+    }
+
+    /// Consume the `TaggedMaybeBox<T, BITS>` and return the inner `T`.
+    pub fn into_inner(self) -> T {
+        self.into_parts().0
+    }
+}
+
+impl<T: NoUninit, const BITS: u32> TaggedMaybeBox<T, BITS> {
+    /// Consume the `TaggedMaybeBox<T, BITS>` and return the word it's stored in, for passing to C
+    /// code as a `void *`. The tag can be recovered from the low `BITS` bits of the word.
+    ///
+    /// The word must eventually be turned back into a `TaggedMaybeBox<T, BITS>` with `from_raw`.
+    /// See the crate docs on raw words.
+    pub fn into_raw(self) -> *mut c_void {
+        let this = ManuallyDrop::new(self);
+        unsafe { read_raw::<T, _>(this.data.as_ptr() as *const *mut c_void) }
+    }
+}
+
+impl<T, const BITS: u32> TaggedMaybeBox<T, BITS> {
+    /// The same as `into_raw`, for a `T` that's boxed, so that it doesn't need to implement
+    /// `NoUninit`. Fails to compile if a `T` would be stored inline alongside the tag.
+    pub fn into_boxed_raw(self) -> *mut c_void {
+        const {
+            assert!(
+                is_zst::<T>() || !fits_tagged::<T>(BITS),
+                "value is stored inline, so `into_boxed_raw` can't be used",
+            )
+        };
+        let this = ManuallyDrop::new(self);
+        unsafe { read_boxed_raw(this.data.as_ptr() as *const *mut c_void) }
+    }
+
+    /// Reconstruct a `TaggedMaybeBox<T, BITS>` from a word returned by `into_raw` or
+    /// `into_boxed_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `TaggedMaybeBox::<T, BITS>::into_raw` or
+    /// `TaggedMaybeBox::<T, BITS>::into_boxed_raw` for the same `T` and `BITS`, and not already
+    /// turned back into a `TaggedMaybeBox<T, BITS>`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> TaggedMaybeBox<T, BITS> {
+        TaggedMaybeBox {
+            data: data_from_raw(raw),
+            _ph: PhantomData,
+        }
+    }
+}
+
+impl<T, const BITS: u32> Drop for TaggedMaybeBox<T, BITS> {
+    fn drop(&mut self) {
+        unsafe {
+            let ptr = TaggedMaybeBox::<T, BITS>::value_ptr(&mut self.data);
+            if is_zst::<T>() || fits_tagged::<T>(BITS) {
+                ptr::drop_in_place(ptr);
+            } else {
+                drop(Box::from_raw(ptr));
+            }
+        }
+    }
+}
+
+impl<T, const BITS: u32> Deref for TaggedMaybeBox<T, BITS> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*TaggedMaybeBox::<T, BITS>::value_ptr(self.data.as_ptr() as *mut _) }
+    }
+}
+
+impl<T, const BITS: u32> DerefMut for TaggedMaybeBox<T, BITS> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *TaggedMaybeBox::<T, BITS>::value_ptr(&mut self.data) }
+    }
+}
+
+impl<T: fmt::Debug, const BITS: u32> fmt::Debug for TaggedMaybeBox<T, BITS> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner: &T = self;
+        f.debug_struct("TaggedMaybeBox")
+            .field("value", inner)
+            .field("tag", &self.tag())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn inline() {
+        let mut mb = TaggedMaybeBox::<u16, 3>::new(0xffff, 5);
+        assert!(fits_tagged::<u16>(3));
+        assert_eq!(*mb, 0xffff);
+        assert_eq!(mb.tag(), 5);
+        mb.set_tag(7);
+        *mb = 0x1234;
+        assert_eq!(mb.tag(), 7);
+        assert_eq!(mb.into_raw() as usize & 7, 7);
+
+        let mb = TaggedMaybeBox::<[u8; 7], 8>::new([1; 7], 0xff);
+        assert_eq!(mb.tag(), 0xff);
+        assert_eq!(mb.into_parts(), ([1; 7], 0xff));
+
+        let mut mb = TaggedMaybeBox::<(), 4>::new((), 15);
+        mb.set_tag(0);
+        assert_eq!(mb.tag(), 0);
+    }
+
+    #[test]
+    fn boxed() {
+        let rc = Rc::new(());
+        let mut mb = TaggedMaybeBox::<(u64, Rc<()>), 3>::new((1, rc.clone()), 3);
+        assert!(!fits_tagged::<(u64, Rc<()>)>(3));
+        assert_eq!(mb.0, 1);
+        mb.0 += 1;
+        mb.set_tag(6);
+        assert_eq!(mb.0, 2);
+        assert_eq!(mb.tag(), 6);
+        let debug = format!("{:?}", TaggedMaybeBox::<u64, 2>::new(4, 1));
+        assert_eq!(debug, "TaggedMaybeBox { value: 4, tag: 1 }");
+
+        drop(mb);
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let mb = TaggedMaybeBox::<[Rc<()>; 2], 3>::new([rc.clone(), rc.clone()], 5);
+        let raw = mb.into_raw();
+        assert_eq!(raw as usize & 7, 5);
+        let mb = unsafe { TaggedMaybeBox::<[Rc<()>; 2], 3>::from_raw(raw) };
+        assert_eq!(Rc::strong_count(&rc), 3);
+        assert_eq!(mb.tag(), 5);
+        drop(mb);
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let mb = TaggedMaybeBox::<String, 3>::new(String::from("hello"), 2);
+        let raw = mb.into_boxed_raw();
+        assert_eq!(raw as usize & 7, 2);
+        let mb = unsafe { TaggedMaybeBox::<String, 3>::from_raw(raw) };
+        assert_eq!(mb.into_parts(), (String::from("hello"), 2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn tag_too_big() {
+        TaggedMaybeBox::<u8, 2>::new(0, 4);
+    }
+}
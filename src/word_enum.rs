@@ -0,0 +1,260 @@
+//! Support for enums packed into a single word, as generated by `#[derive(WordEnum)]`.
+//!
+//! The derive macro lives in the `maybe_box_derive` crate and is re-exported as
+//! `maybe_box::WordEnum` when the `derive` feature is enabled. For an enum `Foo` whose variants
+//! each have either no fields or a single unnamed field, it generates a `FooWord` struct holding a
+//! packed `Foo` in the space for a `usize`, along with an implementation of `WordEnum`:
+//!
+#![cfg_attr(feature = "derive", doc = "```")]
+#![cfg_attr(not(feature = "derive"), doc = "```ignore")]
+//! use maybe_box::WordEnum;
+//!
+//! struct Connection {
+//!     addr: String,
+//!     port: u16,
+//! }
+//!
+//! #[derive(WordEnum)]
+//! enum Userdata {
+//!     Handle(u32),
+//!     #[word_enum(boxed)]
+//!     Conn(Connection),
+//!     Closed,
+//! }
+//!
+//! let word: UserdataWord = Userdata::Handle(3).pack();
+//! assert_eq!(word.as_handle(), Some(&3));
+//! assert!(!word.is_closed());
+//!
+//! let conn = Connection { addr: String::from("localhost"), port: 80 };
+//! let word = Userdata::Conn(conn).pack();
+//! assert_eq!(word.as_conn().map(|c| c.port), Some(80));
+//!
+//! let raw = word.into_raw();
+//! let word = unsafe { UserdataWord::from_raw(raw) };
+//! assert_eq!(word.as_conn().map(|c| &c.addr[..]), Some("localhost"));
+//! ```
+//!
+//! The discriminant is stored in the low bits of the word. Each variant's field is stored inline
+//! in the remaining bytes if it fits, like `MaybeEither` stores its values, and is boxed
+//! otherwise. `FooWord` has methods `is_<variant>` for each variant and
+//! `as_<variant>`/`as_<variant>_mut` for each variant with a field, with the variant names
+//! converted to snake case, as well as `unpack`, `into_raw` and `from_raw`.
+//!
+//! `into_raw` requires the field of each variant to implement `NoUninit`, since an inline field is
+//! part of the word. A field that's always boxed, such as `Connection` above, doesn't need to, if
+//! its variant is marked `#[word_enum(boxed)]`. That fails to compile if the field would be stored
+//! inline.
+//!
+//! Boxes are allocated with an alignment of at least `1 << TAG_BITS` so that the discriminant
+//! doesn't overlap the pointer. This is the alignment of a `usize`, so an enum can have at most
+//! `MAX_VARIANTS` variants: 8 on targets with 64-bit words and 4 on targets with 32-bit words.
+//! Deriving `WordEnum` for an enum with more variants than that fails to compile.
+
+use std::alloc::{self, Layout};
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::os::raw::c_void;
+use std::ptr::{self, NonNull};
+
+use {is_zst, read_raw, read_boxed_raw, data_from_raw, NoUninit};
+use tagged::{fits_tagged, tag_offset, value_offset};
+
+/// The number of low bits of a word available for a discriminant.
+pub const TAG_BITS: u32 = mem::align_of::<usize>().trailing_zeros();
+
+/// The most variants a `WordEnum` can have.
+pub const MAX_VARIANTS: usize = 1 << TAG_BITS;
+
+/// An enum that can be packed into a single word. This is implemented by `#[derive(WordEnum)]`.
+pub trait WordEnum: Sized {
+    /// The packed representation of the enum.
+    type Packed;
+
+    /// Pack the enum into a word, boxing the field of the variant if it doesn't fit.
+    fn pack(self) -> Self::Packed;
+
+    /// Recover the enum from its packed representation.
+    fn unpack(packed: Self::Packed) -> Self;
+}
+
+/// A word holding a value of one of several types along with a `BITS`-bit tag saying which. This
+/// is the storage used by `#[derive(WordEnum)]` and `MaybeEither`.
+///
+/// `RawWord` doesn't know the type of the value it holds, so it's up to the owner to drop the
+/// value with `drop_in_place` or move it out with `take`; otherwise the value is leaked. `E` is
+/// the type which the value came from, typically an enum with the value as the field of one of its
+/// variants, and a `RawWord<E, BITS>` is `Send` or `Sync` if `E` is. That's why `new` is unsafe.
+#[repr(transparent)]
+pub struct RawWord<E, const BITS: u32> {
+    data: MaybeUninit<usize>,
+    _ph: PhantomData<E>,
+}
+
+unsafe impl<E: Send, const BITS: u32> Send for RawWord<E, BITS> {}
+unsafe impl<E: Sync, const BITS: u32> Sync for RawWord<E, BITS> {}
+
+impl<E, const BITS: u32> RawWord<E, BITS> {
+    /// The layout used to box a `T`, which leaves the low `BITS` bits of the pointer free.
+    #[inline]
+    fn boxed_layout<T>() -> Layout {
+        match Layout::new::<T>().align_to(1 << BITS) {
+            Ok(layout) => layout,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Store `t` in a word with the given tag. `t` is stored inline if it fits in the bytes of the
+    /// word that don't hold the tag, and is boxed otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tag` doesn't fit in `BITS` bits.
+    ///
+    /// # Safety
+    ///
+    /// `T` must be the type of a field of `E`, or `()`. The `RawWord` is `Send` and `Sync` based
+    /// on `E` alone, so storing any other type could send it across threads when it isn't `Send`.
+    #[inline]
+    pub unsafe fn new<T>(t: T, tag: usize) -> RawWord<E, BITS> {
+        const {
+            assert!(BITS >= 1, "there must be at least one tag bit");
+            assert!(BITS <= TAG_BITS, "too many tag bits for the alignment of a word");
+        };
+        assert!(tag < 1 << BITS, "tag {} doesn't fit in {} bits", tag, BITS);
+
+        let mut data = MaybeUninit::<usize>::zeroed();
+        unsafe {
+            if is_zst::<T>() {
+                ptr::write(NonNull::<T>::dangling().as_ptr(), t);
+            } else if fits_tagged::<T>(BITS) {
+                let ptr = (data.as_mut_ptr() as *mut u8).add(value_offset::<T>(BITS));
+                ptr::write(ptr as *mut T, t);
+            } else {
+                let layout = RawWord::<E, BITS>::boxed_layout::<T>();
+                let ptr = alloc::alloc(layout) as *mut T;
+                if ptr.is_null() {
+                    alloc::handle_alloc_error(layout);
+                }
+                ptr::write(ptr, t);
+                ptr::write(data.as_mut_ptr() as *mut *mut T, ptr);
+            }
+
+            if is_zst::<T>() || fits_tagged::<T>(BITS) {
+                *(data.as_mut_ptr() as *mut u8).add(tag_offset(BITS)) |= tag as u8;
+            } else {
+                let ptr = data.as_mut_ptr() as *mut *mut T;
+                ptr::write(ptr, ptr::read(ptr).map_addr(|addr| addr | tag));
+            }
+        }
+        RawWord {
+            data,
+            _ph: PhantomData,
+        }
+    }
+
+    /// Get the tag.
+    pub fn tag(&self) -> usize {
+        // The tag fits in a byte, and the byte holding it is always initialized whatever the word
+        // holds.
+        let byte = unsafe { *(self.data.as_ptr() as *const u8).add(tag_offset(BITS)) };
+        byte as usize & ((1 << BITS) - 1)
+    }
+
+    /// Get a pointer to the `T` stored in the word at `data`.
+    #[inline]
+    unsafe fn value_ptr<T>(data: *mut MaybeUninit<usize>) -> *mut T {
+        if is_zst::<T>() {
+            NonNull::dangling().as_ptr()
+        } else if fits_tagged::<T>(BITS) {
+            (data as *mut u8).add(value_offset::<T>(BITS)) as *mut T
+        } else {
+            ptr::read(data as *const *mut T).map_addr(|addr| addr & !((1 << BITS) - 1))
+        }
+    }
+
+    /// Borrow the value.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been stored as a `T` and not yet dropped or taken.
+    pub unsafe fn get<T>(&self) -> &T {
+        &*RawWord::<E, BITS>::value_ptr(self.data.as_ptr() as *mut MaybeUninit<usize>)
+    }
+
+    /// Mutably borrow the value.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been stored as a `T` and not yet dropped or taken.
+    pub unsafe fn get_mut<T>(&mut self) -> &mut T {
+        &mut *RawWord::<E, BITS>::value_ptr(&mut self.data)
+    }
+
+    /// Move the value out of the word, freeing its box if it has one.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been stored as a `T` and not yet dropped or taken.
+    pub unsafe fn take<T>(mut self) -> T {
+        let ptr = RawWord::<E, BITS>::value_ptr::<T>(&mut self.data);
+        let t = ptr::read(ptr);
+        if !is_zst::<T>() && !fits_tagged::<T>(BITS) {
+            alloc::dealloc(ptr as *mut u8, RawWord::<E, BITS>::boxed_layout::<T>());
+        }
+        t
+    }
+
+    /// Drop the value, freeing its box if it has one. The word must not be used afterwards.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been stored as a `T` and not yet dropped or taken.
+    pub unsafe fn drop_in_place<T>(&mut self) {
+        let ptr = RawWord::<E, BITS>::value_ptr::<T>(&mut self.data);
+        ptr::drop_in_place(ptr);
+        if !is_zst::<T>() && !fits_tagged::<T>(BITS) {
+            alloc::dealloc(ptr as *mut u8, RawWord::<E, BITS>::boxed_layout::<T>());
+        }
+    }
+
+    /// Consume the `RawWord` and return the word as a `void *`, with the tag in its low `BITS`
+    /// bits.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been stored as a `T` and not yet dropped or taken.
+    pub unsafe fn into_raw<T: NoUninit>(self) -> *mut c_void {
+        read_raw::<T, _>(self.data.as_ptr() as *const *mut c_void)
+    }
+
+    /// The same as `into_raw`, for a `T` that's boxed, so that it doesn't need to implement
+    /// `NoUninit`. Fails to compile if a `T` would be stored inline alongside the tag.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been stored as a `T` and not yet dropped or taken.
+    pub unsafe fn into_boxed_raw<T>(self) -> *mut c_void {
+        const {
+            assert!(
+                is_zst::<T>() || !fits_tagged::<T>(BITS),
+                "value is stored inline, so `into_boxed_raw` can't be used",
+            )
+        };
+        read_boxed_raw(self.data.as_ptr() as *const *mut c_void)
+    }
+
+    /// Reconstruct a `RawWord` from a word returned by `into_raw` or `into_boxed_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `RawWord::<E, BITS>::into_raw` or
+    /// `RawWord::<E, BITS>::into_boxed_raw` for the same `E` and `BITS`, and not already turned
+    /// back into a `RawWord`.
+    pub unsafe fn from_raw(raw: *mut c_void) -> RawWord<E, BITS> {
+        RawWord {
+            data: data_from_raw(raw),
+            _ph: PhantomData,
+        }
+    }
+}
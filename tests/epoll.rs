@@ -0,0 +1,59 @@
+#![cfg(target_os = "linux")]
+
+extern crate libc;
+extern crate maybe_box;
+
+use maybe_box::{MaybeBox64, Unpacked};
+
+/// Register `data` with an epoll instance watching a ready eventfd, and return the `u64` that
+/// comes back in the event.
+fn round_trip_through_epoll(data: u64) -> u64 {
+    unsafe {
+        let epfd = libc::epoll_create1(libc::EPOLL_CLOEXEC);
+        assert!(epfd >= 0);
+        let efd = libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK);
+        assert!(efd >= 0);
+
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: data,
+        };
+        assert_eq!(libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, efd, &mut event), 0);
+
+        let one = 1u64;
+        assert_eq!(libc::write(efd, &one as *const u64 as *const libc::c_void, 8), 8);
+
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }];
+        assert_eq!(libc::epoll_wait(epfd, events.as_mut_ptr(), 1, 1000), 1);
+
+        libc::close(efd);
+        libc::close(epfd);
+        events[0].u64
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn epoll_user_data() {
+    let raw = round_trip_through_epoll(MaybeBox64::new([12u32, 34]).into_u64());
+    let mb = unsafe { MaybeBox64::<[u32; 2]>::from_u64(raw) };
+    match mb.unpack() {
+        Unpacked::Inline(t) => assert_eq!(t, [12, 34]),
+        x => panic!("Unexpected!: {:?}", x),
+    };
+
+    let raw = round_trip_through_epoll(MaybeBox64::new([1u64, 2, 3]).into_u64());
+    let mb = unsafe { MaybeBox64::<[u64; 3]>::from_u64(raw) };
+    assert_eq!(mb.into_inner(), [1, 2, 3]);
+
+    // A connection's state, which is boxed and doesn't need to be `NoUninit`.
+    struct Connection {
+        name: String,
+        fd: i32,
+    }
+
+    let conn = Connection { name: String::from("eventfd"), fd: 7 };
+    let raw = round_trip_through_epoll(MaybeBox64::new(conn).into_boxed_u64());
+    let conn = unsafe { MaybeBox64::<Connection>::from_boxed_u64(raw) }.into_inner();
+    assert_eq!((&conn.name[..], conn.fd), ("eventfd", 7));
+}
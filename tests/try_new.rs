@@ -0,0 +1,74 @@
+extern crate maybe_box;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::ptr;
+
+use maybe_box::{AllocError, MaybeBox, MaybeBox64};
+
+/// The system allocator, except that allocations fail on threads which have set `FAIL`.
+struct FailingAlloc;
+
+thread_local! {
+    static FAIL: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for FailingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if FAIL.with(|f| f.get()) {
+            ptr::null_mut()
+        } else {
+            System.alloc(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: FailingAlloc = FailingAlloc;
+
+/// Run `f` with allocations on this thread failing.
+fn failing<R, F: FnOnce() -> R>(f: F) -> R {
+    FAIL.with(|fail| fail.set(true));
+    let r = f();
+    FAIL.with(|fail| fail.set(false));
+    r
+}
+
+#[test]
+fn try_new() {
+    let res = failing(|| MaybeBox::try_new([7u8; 100]));
+    match res {
+        Err((t, AllocError)) => assert_eq!(t, [7; 100]),
+        Ok(_) => panic!("allocation should have failed"),
+    }
+
+    let mb = failing(|| MaybeBox::try_new(7u32)).unwrap();
+    assert_eq!(*mb, 7);
+    let mb = failing(|| MaybeBox64::try_new(())).unwrap();
+    assert_eq!(*mb, ());
+
+    let mb = MaybeBox::try_new(vec![1, 2, 3]).unwrap();
+    assert_eq!(*mb, [1, 2, 3]);
+}
+
+#[test]
+fn try_new_with() {
+    let mut called = false;
+    let res = failing(|| {
+        MaybeBox::<[u64; 4]>::try_new_with(|| {
+            called = true;
+            [0; 4]
+        })
+    });
+    assert_eq!(res.err(), Some(AllocError));
+    assert!(!called);
+
+    let mb = failing(|| MaybeBox::try_new_with(|| 5u16)).unwrap();
+    assert_eq!(*mb, 5);
+    let mb = MaybeBox::try_new_with(|| [9u64; 4]).unwrap();
+    assert_eq!(mb.into_inner(), [9; 4]);
+}
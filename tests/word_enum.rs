@@ -0,0 +1,105 @@
+extern crate maybe_box;
+extern crate maybe_box_derive;
+
+use std::mem;
+use std::rc::Rc;
+
+use maybe_box::word_enum::WordEnum;
+use maybe_box_derive::WordEnum;
+
+#[derive(Debug, PartialEq, WordEnum)]
+enum Userdata {
+    Handle(u32),
+    Conn([u64; 4]),
+    Shared(Rc<String>),
+    Closed,
+}
+
+#[derive(Debug, PartialEq, WordEnum)]
+enum Padded {
+    Pair((u8, u16)),
+    Empty,
+}
+
+/// A connection's state, which has padding and a `String`, so it isn't `NoUninit`.
+#[derive(Debug, PartialEq)]
+struct Connection {
+    addr: String,
+    port: u16,
+}
+
+#[derive(Debug, PartialEq, WordEnum)]
+enum Socket {
+    Handle(u32),
+    #[word_enum(boxed)]
+    Conn(Connection),
+    Closed,
+}
+
+#[derive(Debug, PartialEq, WordEnum)]
+pub enum Flag {
+    On,
+    Off,
+}
+
+#[test]
+fn pack_and_unpack() {
+    assert_eq!(mem::size_of::<UserdataWord>(), mem::size_of::<usize>());
+
+    let mut word = Userdata::Handle(3).pack();
+    assert!(word.is_handle());
+    assert!(!word.is_closed());
+    assert_eq!(word.as_handle(), Some(&3));
+    assert_eq!(word.as_conn(), None);
+    *word.as_handle_mut().unwrap() += 1;
+    assert_eq!(word.unpack(), Userdata::Handle(4));
+
+    let mut word = UserdataWord::from(Userdata::Conn([1, 2, 3, 4]));
+    assert!(word.is_conn());
+    word.as_conn_mut().unwrap()[0] = 5;
+    let raw = word.into_raw();
+    let word = unsafe { UserdataWord::from_raw(raw) };
+    assert_eq!(Userdata::unpack(word), Userdata::Conn([5, 2, 3, 4]));
+
+    let word = Userdata::Closed.pack();
+    assert!(word.is_closed());
+    assert_eq!(word.unpack(), Userdata::Closed);
+
+    assert!(Flag::Off.pack().is_off());
+    assert_eq!(Flag::On.pack().unpack(), Flag::On);
+    let raw = Flag::Off.pack().into_raw();
+    assert_eq!(unsafe { FlagWord::from_raw(raw) }.unpack(), Flag::Off);
+
+    // `(u8, u16)` has a padding byte, so this can be packed but not turned into a raw word.
+    let word = Padded::Pair((1, 2)).pack();
+    assert_eq!(word.as_pair(), Some(&(1, 2)));
+    assert_eq!(word.unpack(), Padded::Pair((1, 2)));
+    assert!(Padded::Empty.pack().is_empty());
+}
+
+#[test]
+fn boxed_raw() {
+    let conn = Connection { addr: String::from("localhost"), port: 80 };
+    let mut word = Socket::Conn(conn).pack();
+    word.as_conn_mut().unwrap().port = 81;
+    let raw = word.into_raw();
+    let word = unsafe { SocketWord::from_raw(raw) };
+    assert_eq!(word.as_conn().map(|c| c.port), Some(81));
+    let conn = Connection { addr: String::from("localhost"), port: 81 };
+    assert_eq!(word.unpack(), Socket::Conn(conn));
+
+    let raw = Socket::Handle(3).pack().into_raw();
+    assert_eq!(unsafe { SocketWord::from_raw(raw) }.unpack(), Socket::Handle(3));
+    let raw = Socket::Closed.pack().into_raw();
+    assert!(unsafe { SocketWord::from_raw(raw) }.is_closed());
+}
+
+#[test]
+fn drops() {
+    let rc = Rc::new(String::from("hello"));
+    let word = Userdata::Shared(rc.clone()).pack();
+    assert_eq!(word.as_shared().map(|s| &s[..]), Some("hello"));
+    assert_eq!(Rc::strong_count(&rc), 2);
+    drop(word);
+    assert_eq!(Rc::strong_count(&rc), 1);
+}